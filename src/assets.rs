@@ -4,12 +4,7 @@ use resvg::raqote::DrawTarget;
 use std::convert::TryInto;
 use std::path::{Path, PathBuf};
 
-use arrayvec::ArrayVec;
-use ggez::graphics::spritebatch::SpriteBatch;
 use ggez::graphics::Image;
-use ggez::nalgebra as na;
-
-use crate::keyboard::{BaseKeyboard, Key, KeyType, LAYOUT};
 
 /// Render an svg to a raqote `DrawTarget`
 /// so we can get it as a &[u8] to upload to a texture.
@@ -38,8 +33,6 @@ pub struct Assets {
     pub black_key: Image,
     pub white_key_active: Image,
     pub black_key_active: Image,
-    pub keyboard: BaseKeyboard,
-    pub keymap: ArrayVec<[Key; 100]>,
 }
 
 impl Assets {
@@ -48,47 +41,11 @@ impl Assets {
         let black_key = render_img(ctx, p.join("black_key.svg"));
         let white_key_active = render_img(ctx, p.join("white_key_active.svg"));
         let black_key_active = render_img(ctx, p.join("black_key_active.svg"));
-        let (keyboard, keymap) = Self::gen_piano(&white_key, &black_key);
         Self {
             white_key,
             black_key,
             white_key_active,
             black_key_active,
-            keyboard,
-            keymap,
-        }
-    }
-
-    // TODO: move this to `keyboard`.
-    // It's kinda awkward having the keyboard generation code here, but the rendering there.
-    pub fn gen_piano(
-        white_key: &Image,
-        black_key: &Image,
-    ) -> ((SpriteBatch, SpriteBatch), ArrayVec<[Key; 100]>) {
-        let mut pos = 0;
-        let mut white_batch = SpriteBatch::new(white_key.clone());
-        let mut black_batch = SpriteBatch::new(black_key.clone());
-        let white_dist = white_key.width();
-        let black_dist = black_key.width() / 2;
-        let mut keymap: ArrayVec<[Key; 100]> = ArrayVec::new();
-        for i in LAYOUT.chars() {
-            if i == 'W' {
-                let pt = na::Point2::new(pos as f32, 0.0);
-                white_batch.add((pt,));
-                pos += white_dist;
-                keymap.push(Key {
-                    key_type: KeyType::WHITE,
-                    offset: pt,
-                });
-            } else {
-                let pt = na::Point2::new((pos - black_dist) as f32, 0.0);
-                black_batch.add((pt,));
-                keymap.push(Key {
-                    key_type: KeyType::BLACK,
-                    offset: pt,
-                });
-            }
         }
-        ((white_batch, black_batch), keymap)
     }
 }
@@ -0,0 +1,282 @@
+use rodio::Source;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::Duration;
+use std::vec::Vec;
+
+use crate::midi_interpreter::DRUM_CHANNEL;
+use crate::song::{Song, Tile};
+use crate::synth::{write_wav, Synth, SynthCommand, Timbre, SAMPLE_RATE};
+
+// How many samples to render between checks for new commands.
+const COMMAND_INTERVAL: usize = 64;
+
+/// Feeds the synth to rodio, which pulls samples from its own output thread.
+struct SynthSource {
+    synth: Synth,
+    commands: Receiver<SynthCommand>,
+    counter: usize,
+}
+
+impl Iterator for SynthSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.counter % COMMAND_INTERVAL == 0 {
+            for cmd in self.commands.try_iter() {
+                self.synth.handle(cmd);
+            }
+        }
+        self.counter = self.counter.wrapping_add(1);
+        Some(self.synth.next_sample())
+    }
+}
+
+impl Source for SynthSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        1
+    }
+
+    fn sample_rate(&self) -> u32 {
+        SAMPLE_RATE
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+pub struct AudioEngine {
+    commands: Sender<SynthCommand>,
+    pub timbre: Timbre,
+    /// Play the song's notes as they reach the keyboard line.
+    pub play_song: bool,
+    /// Play the notes coming in from the keyboard.
+    pub play_input: bool,
+}
+
+impl AudioEngine {
+    /// Start playing the synth on the default output device, if there is one.
+    pub fn new() -> Option<Self> {
+        let device = match rodio::default_output_device() {
+            Some(device) => device,
+            None => {
+                println!("No audio output device found, running silently.");
+                return None;
+            }
+        };
+        let (commands, receiver) = channel();
+        rodio::play_raw(
+            &device,
+            SynthSource {
+                synth: Synth::new(SAMPLE_RATE),
+                commands: receiver,
+                counter: 0,
+            },
+        );
+        Some(Self {
+            commands,
+            timbre: Timbre::Piano,
+            play_song: true,
+            play_input: true,
+        })
+    }
+
+    pub fn send(&self, cmd: SynthCommand) {
+        let _ = self.commands.send(cmd);
+    }
+
+    pub fn set_timbre(&mut self, timbre: Timbre) {
+        self.timbre = timbre;
+        self.send(SynthCommand::SetTimbre(timbre));
+    }
+}
+
+/// Somewhere the sequencer can play a song's notes.
+pub trait NoteSink {
+    /// Whether to play the notes the player is learning.
+    fn plays_song(&self) -> bool;
+    fn plays_accompaniment(&self) -> bool;
+    /// Start `tile`, which is due at `tile.start`.
+    fn note_on(&mut self, tile: &Tile);
+    /// End a note, which is due at song time `at`.
+    fn note_off(&mut self, channel: u8, key: u8, at: Duration);
+    fn all_notes_off(&mut self);
+}
+
+impl NoteSink for AudioEngine {
+    fn plays_song(&self) -> bool {
+        self.play_song
+    }
+
+    fn plays_accompaniment(&self) -> bool {
+        true
+    }
+
+    fn note_on(&mut self, tile: &Tile) {
+        // The synth only has pitched sounds, so drum parts would come out as random notes.
+        if tile.channel == DRUM_CHANNEL {
+            return;
+        }
+        self.send(SynthCommand::NoteOn {
+            key: tile.note,
+            vel: tile.velocity,
+        });
+    }
+
+    fn note_off(&mut self, channel: u8, key: u8, _at: Duration) {
+        if channel != DRUM_CHANNEL {
+            self.send(SynthCommand::NoteOff { key });
+        }
+    }
+
+    fn all_notes_off(&mut self) {
+        self.send(SynthCommand::AllNotesOff);
+    }
+}
+
+/// Turns a song's tiles into note on/off events for a `NoteSink` as the song plays.
+#[derive(Default)]
+pub struct Sequencer {
+    next: usize,
+    next_accompaniment: usize,
+    releases: Vec<(Duration, u8, u8)>,
+}
+
+impl Sequencer {
+    /// Send every note starting or ending before `now`.
+    pub fn update<S: NoteSink>(&mut self, song: &Song, now: Duration, sink: &mut S) {
+        let mut released = vec![];
+        self.releases.retain(|&(end, channel, key)| {
+            if end <= now {
+                released.push((end, channel, key));
+                false
+            } else {
+                true
+            }
+        });
+        for (end, channel, key) in released {
+            sink.note_off(channel, key, end);
+        }
+        let (song_audible, accompaniment_audible) = (sink.plays_song(), sink.plays_accompaniment());
+        let releases = &mut self.releases;
+        let mut play = |tiles: &[Tile], next: &mut usize, audible: bool| {
+            while let Some(tile) = tiles.get(*next) {
+                if tile.start > now {
+                    break;
+                }
+                if audible {
+                    sink.note_on(tile);
+                    releases.push((tile.end(), tile.channel, tile.note));
+                }
+                *next += 1;
+            }
+        };
+        play(&song.tiles, &mut self.next, song_audible);
+        play(
+            &song.accompaniment,
+            &mut self.next_accompaniment,
+            accompaniment_audible,
+        );
+    }
+
+    /// Silence everything and continue from `pos` in the song.
+    pub fn seek<S: NoteSink>(&mut self, song: &Song, pos: Duration, sink: Option<&mut S>) {
+        let first = |tiles: &[Tile]| tiles.iter().take_while(|i| i.start < pos).count();
+        self.next = first(&song.tiles);
+        self.next_accompaniment = first(&song.accompaniment);
+        self.releases.clear();
+        if let Some(sink) = sink {
+            sink.all_notes_off();
+        }
+    }
+}
+
+/// Render `tiles` through the synth without a sound card.
+pub fn render_tiles(tiles: &[Tile], timbre: Timbre) -> Vec<f32> {
+    let mut events: Vec<(Duration, SynthCommand)> = vec![];
+    for tile in tiles.iter().filter(|i| i.channel != DRUM_CHANNEL) {
+        let key = tile.note;
+        events.push((
+            tile.start,
+            SynthCommand::NoteOn {
+                key,
+                vel: tile.velocity,
+            },
+        ));
+        events.push((tile.start + tile.length, SynthCommand::NoteOff { key }));
+    }
+    events.sort_by_key(|i| i.0);
+
+    let mut synth = Synth::new(SAMPLE_RATE);
+    synth.handle(SynthCommand::SetTimbre(timbre));
+    let mut samples: Vec<f32> = vec![];
+    for (time, cmd) in events {
+        let pos = (time.as_secs_f64() * SAMPLE_RATE as f64) as usize;
+        if pos > samples.len() {
+            let start = samples.len();
+            samples.resize(pos, 0.0);
+            synth.render(&mut samples[start..]);
+        }
+        synth.handle(cmd);
+    }
+    // Leave room for the last notes to ring out.
+    let start = samples.len();
+    samples.resize(start + SAMPLE_RATE as usize * 2, 0.0);
+    synth.render(&mut samples[start..]);
+    samples
+}
+
+/// Render `tiles` to a WAV file at `path`.
+pub fn export_wav(tiles: &[Tile], timbre: Timbre, path: &Path) -> io::Result<()> {
+    let samples = render_tiles(tiles, timbre);
+    write_wav(BufWriter::new(File::create(path)?), &samples, SAMPLE_RATE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_covers_the_song_and_its_tail() {
+        let samples = render_tiles(&[Tile::test(60, 500, 500)], Timbre::Piano);
+        let rate = SAMPLE_RATE as usize;
+        // One second of song, then two seconds to ring out.
+        assert_eq!(samples.len(), rate + rate * 2);
+        assert!(samples[..rate / 2].iter().all(|&i| i == 0.0));
+        assert!(samples[rate / 2..rate].iter().any(|&i| i.abs() > 0.01));
+        assert!(samples.iter().all(|i| i.abs() <= 1.0));
+    }
+
+    #[test]
+    fn render_of_nothing_is_silent() {
+        let samples = render_tiles(&[], Timbre::Organ);
+        assert_eq!(samples.len(), SAMPLE_RATE as usize * 2);
+        assert!(samples.iter().all(|&i| i == 0.0));
+    }
+
+    #[test]
+    fn drums_are_left_out_of_renders() {
+        let mut drum = Tile::test(38, 0, 500);
+        drum.channel = DRUM_CHANNEL;
+        let samples = render_tiles(&[drum], Timbre::Piano);
+        assert!(samples.iter().all(|&i| i == 0.0));
+    }
+
+    #[test]
+    fn wav_has_a_header_and_two_bytes_per_sample() {
+        let samples = render_tiles(&[Tile::test(60, 0, 100)], Timbre::ElectricPiano);
+        let mut out = vec![];
+        write_wav(&mut out, &samples, SAMPLE_RATE).unwrap();
+        assert_eq!(out.len(), 44 + samples.len() * 2);
+        assert_eq!(&out[..4], b"RIFF");
+        assert_eq!(&out[8..16], b"WAVEfmt ");
+        assert_eq!(&out[36..40], b"data");
+    }
+}
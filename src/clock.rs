@@ -0,0 +1,190 @@
+use std::time::{Duration, Instant};
+
+use crate::song::deltat;
+
+/// The song's playback position, which can be paused, resumed and moved around freely.
+pub struct SongClock {
+    playing: bool,
+    rate: f32,
+    /// The real time at which the song was at `anchor_pos`.
+    anchor: Instant,
+    anchor_pos: Duration,
+    /// A point the clock won't advance past, used to wait for the player.
+    hold: Option<Duration>,
+}
+
+impl SongClock {
+    pub fn new() -> Self {
+        Self {
+            playing: true,
+            rate: 1.0,
+            anchor: Instant::now(),
+            anchor_pos: Duration::from_secs(0),
+            hold: None,
+        }
+    }
+
+    /// Song time at the given instant.
+    pub fn position_at(&self, instant: Instant) -> Duration {
+        let pos = self.unheld_position_at(instant);
+        match self.hold {
+            Some(hold) if pos > hold => hold,
+            _ => pos,
+        }
+    }
+
+    fn unheld_position_at(&self, instant: Instant) -> Duration {
+        if self.playing {
+            self.anchor_pos
+                + instant
+                    .saturating_duration_since(self.anchor)
+                    .mul_f32(self.rate)
+        } else {
+            self.anchor_pos
+        }
+    }
+
+    pub fn position(&self) -> Duration {
+        self.position_at(Instant::now())
+    }
+
+    /// How much song time is visible above the keyboard.
+    /// This scales with the rate so tiles always fall at the same speed on screen.
+    pub fn window(&self) -> Duration {
+        deltat().mul_f32(self.rate)
+    }
+
+    /// How far down the screen song time `time` is, with `height` at the keyboard line.
+    pub fn screen_y(&self, time: Duration, height: f32) -> f32 {
+        let ahead = time.as_secs_f32() - self.position().as_secs_f32();
+        height * (1.0 - ahead / self.window().as_secs_f32())
+    }
+
+    /// Stop the clock from advancing past `pos`, or let it run freely again with `None`.
+    /// Releasing a hold carries on from the held position rather than jumping ahead.
+    pub fn hold_at(&mut self, pos: Option<Duration>) {
+        if let Some(hold) = self.hold {
+            if pos.map(|p| p > hold).unwrap_or(true)
+                && self.unheld_position_at(Instant::now()) > hold
+            {
+                self.seek(hold);
+            }
+        }
+        self.hold = pos;
+    }
+
+    /// Whether the clock has reached its hold and is waiting.
+    pub fn is_held(&self) -> bool {
+        self.hold
+            .map(|hold| self.unheld_position_at(Instant::now()) >= hold)
+            .unwrap_or(false)
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    pub fn pause(&mut self) {
+        if self.playing {
+            self.anchor_pos = self.position();
+            self.playing = false;
+        }
+    }
+
+    pub fn resume(&mut self) {
+        if !self.playing {
+            self.anchor = Instant::now();
+            self.playing = true;
+        }
+    }
+
+    pub fn seek(&mut self, pos: Duration) {
+        self.anchor = Instant::now();
+        self.anchor_pos = pos;
+    }
+
+    pub fn rate(&self) -> f32 {
+        self.rate
+    }
+
+    pub fn set_rate(&mut self, rate: f32) {
+        self.seek(self.position());
+        self.rate = rate.max(0.01);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    /// A clock stopped at `pos`, so its position doesn't depend on when the test runs.
+    fn paused_at(pos: Duration) -> SongClock {
+        let mut clock = SongClock::new();
+        clock.pause();
+        clock.seek(pos);
+        clock
+    }
+
+    #[test]
+    fn runs_in_real_time_from_its_anchor() {
+        let clock = SongClock::new();
+        assert_eq!(clock.position_at(clock.anchor), ms(0));
+        assert_eq!(clock.position_at(clock.anchor + ms(1500)), ms(1500));
+        // Instants from before the clock started don't run it backwards.
+        assert_eq!(clock.position_at(clock.anchor - ms(100)), ms(0));
+    }
+
+    #[test]
+    fn pausing_stops_the_clock_and_resuming_carries_on() {
+        let mut clock = paused_at(ms(2000));
+        assert!(!clock.is_playing());
+        assert_eq!(clock.position_at(Instant::now() + ms(5000)), ms(2000));
+
+        clock.resume();
+        assert!(clock.is_playing());
+        assert_eq!(clock.position_at(clock.anchor), ms(2000));
+        assert_eq!(clock.position_at(clock.anchor + ms(500)), ms(2500));
+    }
+
+    #[test]
+    fn seeking_moves_the_position_either_way() {
+        let mut clock = SongClock::new();
+        clock.seek(ms(5000));
+        assert_eq!(clock.position_at(clock.anchor + ms(1000)), ms(6000));
+        clock.seek(ms(1000));
+        assert_eq!(clock.position_at(clock.anchor), ms(1000));
+
+        let mut paused = paused_at(ms(3000));
+        paused.seek(ms(500));
+        assert_eq!(paused.position(), ms(500));
+        assert!(!paused.is_playing());
+    }
+
+    #[test]
+    fn changing_rate_mid_play_keeps_the_position() {
+        let mut clock = SongClock::new();
+        clock.seek(ms(1000));
+        clock.set_rate(2.0);
+        let from = clock.position_at(clock.anchor);
+        assert!(from >= ms(1000) && from < ms(1100));
+        assert_eq!(clock.position_at(clock.anchor + ms(1000)), from + ms(2000));
+
+        let mut paused = paused_at(ms(1000));
+        paused.set_rate(0.5);
+        assert_eq!(paused.position(), ms(1000));
+        paused.resume();
+        assert_eq!(paused.position_at(paused.anchor + ms(1000)), ms(1500));
+    }
+
+    #[test]
+    fn holds_stop_the_clock_at_a_point() {
+        let mut clock = SongClock::new();
+        clock.hold_at(Some(ms(1000)));
+        assert_eq!(clock.position_at(clock.anchor + ms(5000)), ms(1000));
+        assert_eq!(clock.position_at(clock.anchor + ms(500)), ms(500));
+    }
+}
@@ -0,0 +1,234 @@
+use midir::{MidiInput, MidiInputConnection, MidiInputPort};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
+use std::vec::Vec;
+
+use crate::input::{Decoder, InputEvent, InputKind, StampClock};
+use crate::pairing::SUSTAIN_CONTROLLER;
+
+/// How often to look for devices being plugged in or removed.
+const SCAN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What a device is holding down, so it can be let go of if the device disappears.
+#[derive(Default)]
+struct Held {
+    notes: Vec<(u8, u8)>,
+    pedal: [bool; 16],
+}
+
+impl Held {
+    fn track(&mut self, kind: InputKind) {
+        match kind {
+            InputKind::NoteOn { channel, key, .. } => self.notes.push((channel, key)),
+            InputKind::NoteOff { channel, key, .. } => self.notes.retain(|&i| i != (channel, key)),
+            InputKind::Control {
+                channel,
+                controller,
+                value,
+            } if controller == SUSTAIN_CONTROLLER => {
+                self.pedal[(channel & 0x0F) as usize] = value >= 64
+            }
+            _ => (),
+        }
+    }
+}
+
+struct Connection {
+    name: String,
+    /// The port as the scanner last saw it. A device plugged back in gets a new one.
+    port: MidiInputPort,
+    conn: MidiInputConnection<Held>,
+}
+
+/// Keeps track of the MIDI input ports, and stays connected to the chosen ones
+/// as they come and go.
+pub struct DeviceManager {
+    sender: Sender<InputEvent>,
+    config: PathBuf,
+    /// Kept for the whole run rather than opened for every scan.
+    scanner: Option<MidiInput>,
+    /// Names of every port seen on the last scan.
+    pub available: Vec<String>,
+    /// Names of the ports the user wants to play from.
+    selected: Vec<String>,
+    // We need to keep hold of these, otherwise they'll get dropped and their threads with them.
+    connections: Vec<Connection>,
+    last_scan: Option<Instant>,
+}
+
+impl DeviceManager {
+    /// Load the remembered choice of ports from `config`, falling back to the last port found.
+    pub fn new(sender: Sender<InputEvent>, config: PathBuf) -> Self {
+        let selected = fs::read_to_string(&config)
+            .map(|s| {
+                s.lines()
+                    .map(|i| i.trim().to_string())
+                    .filter(|i| !i.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let mut manager = Self {
+            sender,
+            config,
+            scanner: None,
+            available: vec![],
+            selected,
+            connections: vec![],
+            last_scan: None,
+        };
+        manager.update();
+        if manager.selected.is_empty() {
+            if let Some(name) = manager.available.last().cloned() {
+                manager.selected.push(name);
+                manager.rescan();
+            }
+        }
+        manager
+    }
+
+    pub fn is_selected(&self, name: &str) -> bool {
+        self.selected.iter().any(|i| i == name)
+    }
+
+    pub fn is_connected(&self, name: &str) -> bool {
+        self.connections.iter().any(|i| i.name == name)
+    }
+
+    /// Choose whether to play from the port called `name`, and remember the choice.
+    pub fn set_selected(&mut self, name: &str, selected: bool) {
+        self.selected.retain(|i| i != name);
+        if selected {
+            self.selected.push(name.to_string());
+        }
+        self.save();
+        self.rescan();
+    }
+
+    /// Look for new and removed ports now rather than waiting for the next scan.
+    pub fn rescan(&mut self) {
+        self.last_scan = None;
+        self.update();
+    }
+
+    /// Rescan the ports every so often, dropping connections to removed devices
+    /// and connecting to selected ones that have appeared or been plugged back in.
+    pub fn update(&mut self) {
+        if let Some(at) = self.last_scan {
+            if at.elapsed() < SCAN_INTERVAL {
+                return;
+            }
+        }
+        self.last_scan = Some(Instant::now());
+
+        if self.scanner.is_none() {
+            self.scanner = match MidiInput::new("synthy scanner") {
+                Ok(midi_in) => Some(midi_in),
+                Err(e) => {
+                    println!("Unable to list MIDI devices: {:?}", e);
+                    return;
+                }
+            };
+        }
+        let scanner = match &self.scanner {
+            Some(scanner) => scanner,
+            None => return,
+        };
+        let ports: Vec<(String, MidiInputPort)> = scanner
+            .ports()
+            .into_iter()
+            .filter_map(|p| scanner.port_name(&p).ok().map(|n| (n, p)))
+            .collect();
+        self.available = ports.iter().map(|i| i.0.clone()).collect();
+
+        // A device that was unplugged and plugged back in between scans has the same
+        // name but a different port, and its old connection no longer receives anything.
+        let selected = &self.selected;
+        let (kept, dropped): (Vec<Connection>, Vec<Connection>) = self
+            .connections
+            .drain(..)
+            .partition(|c| selected.contains(&c.name) && ports.iter().any(|p| p.1 == c.port));
+        self.connections = kept;
+        for conn in dropped {
+            println!("Disconnected from {:?}", conn.name);
+            self.release(conn);
+        }
+        let wanted: Vec<(String, MidiInputPort)> = ports
+            .into_iter()
+            .filter(|i| self.selected.contains(&i.0) && !self.is_connected(&i.0))
+            .collect();
+        for (name, port) in wanted {
+            if let Some(conn) = self.connect(&name) {
+                println!("Connected to {:?}", name);
+                self.connections.push(Connection { name, port, conn });
+            }
+        }
+    }
+
+    /// Close a connection, letting go of any notes and pedal it was holding
+    /// so they don't stay stuck down.
+    fn release(&self, conn: Connection) {
+        let (_, held) = conn.conn.close();
+        let time = Instant::now();
+        let releases = held
+            .notes
+            .iter()
+            .map(|&(channel, key)| InputKind::NoteOff {
+                channel,
+                key,
+                vel: 0,
+            })
+            .chain(
+                (0..16u8)
+                    .filter(|&i| held.pedal[i as usize])
+                    .map(|channel| InputKind::Control {
+                        channel,
+                        controller: SUSTAIN_CONTROLLER,
+                        value: 0,
+                    }),
+            );
+        for kind in releases {
+            let _ = self.sender.send(InputEvent { time, kind });
+        }
+    }
+
+    fn connect(&self, name: &str) -> Option<MidiInputConnection<Held>> {
+        // Each connection needs a `MidiInput` of its own.
+        let midi_in = MidiInput::new("synthy reader").ok()?;
+        let port = midi_in
+            .ports()
+            .into_iter()
+            .find(|p| midi_in.port_name(p).map(|n| n == name).unwrap_or(false))?;
+        let sender = self.sender.clone();
+        let mut clock = StampClock::default();
+        let mut decoder = Decoder::default();
+        match midi_in.connect(
+            &port,
+            "synthy-read-input",
+            move |stamp, message, held| {
+                let time = clock.instant(stamp);
+                for kind in decoder.feed(message) {
+                    held.track(kind);
+                    let _ = sender.send(InputEvent { time, kind });
+                }
+            },
+            Held::default(),
+        ) {
+            Ok(conn) => Some(conn),
+            Err(e) => {
+                println!("Unable to connect to {:?}: {:?}", name, e);
+                None
+            }
+        }
+    }
+
+    fn save(&self) {
+        if let Some(dir) = self.config.parent() {
+            let _ = fs::create_dir_all(dir);
+        }
+        if let Err(e) = fs::write(&self.config, self.selected.join("\n")) {
+            println!("Unable to save the device choice: {:?}", e);
+        }
+    }
+}
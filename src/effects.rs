@@ -0,0 +1,338 @@
+use ggez::graphics::{self, Color, DrawMode, DrawParam, MeshBuilder, Rect};
+use ggez::nalgebra as na;
+use ggez::{Context, GameResult};
+use std::time::{Duration, Instant};
+use std::vec::Vec;
+
+use crate::judge::{Judgment, Outcome};
+use crate::layout::PianoLayout;
+
+const GLOW_LIFE: Duration = Duration::from_millis(250);
+const PARTICLE_LIFE: Duration = Duration::from_millis(600);
+const POPUP_LIFE: Duration = Duration::from_millis(700);
+const FLASH_LIFE: Duration = Duration::from_millis(150);
+
+/// Particles thrown up by each hit.
+const BURST: usize = 12;
+/// How high a glow reaches above the keyboard line.
+const GLOW_HEIGHT: f32 = 60.0;
+/// How far popups float up over their life.
+const POPUP_RISE: f32 = 40.0;
+const GRAVITY: f32 = 600.0;
+
+pub fn judgment_color(judgment: Judgment) -> Color {
+    match judgment {
+        Judgment::Perfect => Color::new(1.0, 0.85, 0.2, 1.0),
+        Judgment::Great => Color::new(0.3, 0.6, 1.0, 1.0),
+        Judgment::Good => Color::new(1.0, 1.0, 1.0, 1.0),
+        Judgment::Miss => Color::new(1.0, 0.2, 0.2, 1.0),
+    }
+}
+
+fn with_alpha(color: Color, alpha: f32) -> Color {
+    Color::new(color.r, color.g, color.b, color.a * alpha)
+}
+
+struct Glow {
+    note: u8,
+    color: Color,
+    born: Instant,
+}
+
+struct Particle {
+    origin: (f32, f32),
+    velocity: (f32, f32),
+    color: Color,
+    born: Instant,
+}
+
+struct Popup {
+    text: String,
+    x: f32,
+    color: Color,
+    born: Instant,
+}
+
+/// Feedback drawn over the piano as notes are judged.
+pub struct Effects {
+    pub enabled: bool,
+    pub particles: bool,
+    glows: Vec<Glow>,
+    sparks: Vec<Particle>,
+    popups: Vec<Popup>,
+    flash: Option<Instant>,
+    seed: u32,
+}
+
+impl Effects {
+    pub fn new() -> Self {
+        Self {
+            enabled: true,
+            particles: true,
+            glows: vec![],
+            sparks: vec![],
+            popups: vec![],
+            flash: None,
+            seed: 0x2545_f491,
+        }
+    }
+
+    /// A number from 0 to 1, different every time.
+    fn random(&mut self) -> f32 {
+        // xorshift, which is plenty for scattering particles.
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 17;
+        self.seed ^= self.seed << 5;
+        self.seed as f32 / u32::MAX as f32
+    }
+
+    /// Take everything off the screen, for when the song jumps or stops.
+    pub fn clear(&mut self) {
+        self.glows.clear();
+        self.sparks.clear();
+        self.popups.clear();
+        self.flash = None;
+    }
+
+    /// Show feedback for a judgment, labelled with `label`.
+    pub fn outcome(&mut self, outcome: &Outcome, label: String, layout: &PianoLayout) {
+        if !self.enabled {
+            return;
+        }
+        let now = Instant::now();
+        let color = judgment_color(outcome.judgment());
+        let note = match *outcome {
+            Outcome::Hit { note, .. } | Outcome::Missed { note, .. } | Outcome::Wrong { note } => {
+                note
+            }
+        };
+        let (x, w) = layout
+            .note_x(note)
+            .unwrap_or((layout.screen.x + layout.screen.w / 2.0, 0.0));
+        let centre = x + w / 2.0;
+        self.popups.push(Popup {
+            text: label,
+            x: centre,
+            color,
+            born: now,
+        });
+        if let Outcome::Missed { .. } = outcome {
+            return;
+        }
+        // Every key the player strikes lights up, right or wrong.
+        self.glows.push(Glow {
+            note,
+            color,
+            born: now,
+        });
+        match outcome {
+            Outcome::Hit { .. } => {
+                if self.particles {
+                    let line = layout.keyboard_line();
+                    for _ in 0..BURST {
+                        let angle = std::f32::consts::PI * (1.0 + self.random());
+                        let speed = 150.0 + 250.0 * self.random();
+                        self.sparks.push(Particle {
+                            origin: (centre, line),
+                            velocity: (speed * angle.cos(), speed * angle.sin()),
+                            color,
+                            born: now,
+                        });
+                    }
+                }
+            }
+            Outcome::Wrong { .. } => self.flash = Some(now),
+            Outcome::Missed { .. } => (),
+        }
+    }
+
+    /// Draw what's still showing, along with the player's current `combo`.
+    pub fn draw(&mut self, ctx: &mut Context, layout: &PianoLayout, combo: u32) -> GameResult<()> {
+        let now = Instant::now();
+        let age = |born: Instant, life: Duration| {
+            now.saturating_duration_since(born).as_secs_f32() / life.as_secs_f32()
+        };
+        self.glows.retain(|i| age(i.born, GLOW_LIFE) < 1.0);
+        self.sparks.retain(|i| age(i.born, PARTICLE_LIFE) < 1.0);
+        self.popups.retain(|i| age(i.born, POPUP_LIFE) < 1.0);
+        if self
+            .flash
+            .map(|i| age(i, FLASH_LIFE) >= 1.0)
+            .unwrap_or(false)
+        {
+            self.flash = None;
+        }
+        if !self.enabled {
+            return Ok(());
+        }
+
+        let line = layout.keyboard_line();
+        let mut mesh = MeshBuilder::new();
+        let mut any = false;
+        for glow in self.glows.iter() {
+            let key = match layout.key(glow.note) {
+                Some(key) => key,
+                None => continue,
+            };
+            let fade = 1.0 - age(glow.born, GLOW_LIFE);
+            mesh.rectangle(
+                DrawMode::fill(),
+                key.rect,
+                with_alpha(glow.color, 0.5 * fade),
+            );
+            // A column of light rising from the key, fading towards the top.
+            for step in 0..4 {
+                let h = GLOW_HEIGHT / 4.0;
+                mesh.rectangle(
+                    DrawMode::fill(),
+                    Rect::new(key.rect.x, line - h * (step + 1) as f32, key.rect.w, h),
+                    with_alpha(glow.color, 0.4 * fade * (1.0 - step as f32 / 4.0)),
+                );
+            }
+            any = true;
+        }
+        for spark in self.sparks.iter() {
+            let t = now.saturating_duration_since(spark.born).as_secs_f32();
+            let x = spark.origin.0 + spark.velocity.0 * t;
+            let y = spark.origin.1 + spark.velocity.1 * t + 0.5 * GRAVITY * t * t;
+            let fade = 1.0 - age(spark.born, PARTICLE_LIFE);
+            mesh.rectangle(
+                DrawMode::fill(),
+                Rect::new(x - 2.0, y - 2.0, 4.0, 4.0),
+                with_alpha(spark.color, fade),
+            );
+            any = true;
+        }
+        if let Some(flash) = self.flash {
+            let fade = 1.0 - age(flash, FLASH_LIFE);
+            mesh.rectangle(
+                DrawMode::fill(),
+                layout.screen,
+                Color::new(1.0, 0.1, 0.1, 0.3 * fade),
+            );
+            any = true;
+        }
+        if any {
+            let mesh = mesh.build(ctx)?;
+            graphics::draw(ctx, &mesh, DrawParam::default())?;
+        }
+
+        for popup in self.popups.iter() {
+            let progress = age(popup.born, POPUP_LIFE);
+            let text = graphics::Text::new(popup.text.as_str());
+            let width = text.width(ctx) as f32;
+            let dest = na::Point2::new(
+                popup.x - width / 2.0,
+                line - GLOW_HEIGHT - 20.0 - POPUP_RISE * progress,
+            );
+            graphics::draw(ctx, &text, (dest, with_alpha(popup.color, 1.0 - progress)))?;
+        }
+        if combo > 1 {
+            let text = graphics::Text::new(format!("{} combo", combo));
+            let width = text.width(ctx) as f32;
+            let dest = na::Point2::new(
+                layout.screen.x + layout.screen.w - width - 20.0,
+                line - GLOW_HEIGHT - 20.0,
+            );
+            graphics::draw(ctx, &text, (dest, Color::new(1.0, 1.0, 1.0, 0.9)))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::{KeyProportions, KeyRange};
+
+    /// C4 to E4 on a 300x1000 screen, so D4 is centred at 150.
+    fn layout() -> PianoLayout {
+        PianoLayout::new(
+            KeyRange { low: 60, high: 64 },
+            Rect::new(0.0, 0.0, 300.0, 1000.0),
+            KeyProportions {
+                black_width: 0.5,
+                black_height: 0.6,
+            },
+        )
+    }
+
+    fn hit(note: u8) -> Outcome {
+        Outcome::Hit {
+            tile: 0,
+            note,
+            judgment: Judgment::Perfect,
+            offset: 0.0,
+            velocity: (100, 100),
+        }
+    }
+
+    fn showing(effects: &Effects) -> (usize, usize, usize, bool) {
+        (
+            effects.glows.len(),
+            effects.sparks.len(),
+            effects.popups.len(),
+            effects.flash.is_some(),
+        )
+    }
+
+    #[test]
+    fn hits_glow_and_throw_sparks() {
+        let mut effects = Effects::new();
+        effects.outcome(&hit(62), "Perfect".to_string(), &layout());
+        assert_eq!(showing(&effects), (1, BURST, 1, false));
+        assert_eq!(effects.glows[0].note, 62);
+        assert!((effects.popups[0].x - 150.0).abs() < 1e-3);
+        // Sparks fly upwards from the keyboard line.
+        assert!(effects.sparks.iter().all(|i| i.velocity.1 < 1e-3));
+
+        effects.particles = false;
+        effects.outcome(&hit(64), "Perfect".to_string(), &layout());
+        assert_eq!(showing(&effects), (2, BURST, 2, false));
+    }
+
+    #[test]
+    fn wrong_keys_flash_and_misses_only_pop_up() {
+        let mut effects = Effects::new();
+        effects.outcome(&Outcome::Wrong { note: 60 }, "Wrong".to_string(), &layout());
+        assert_eq!(showing(&effects), (1, 0, 1, true));
+
+        let mut effects = Effects::new();
+        let missed = Outcome::Missed { tile: 0, note: 60 };
+        effects.outcome(&missed, "Miss".to_string(), &layout());
+        assert_eq!(showing(&effects), (0, 0, 1, false));
+    }
+
+    #[test]
+    fn notes_off_the_piano_pop_up_in_the_middle() {
+        let mut effects = Effects::new();
+        effects.outcome(&hit(90), "Perfect".to_string(), &layout());
+        assert!((effects.popups[0].x - 150.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn disabled_effects_show_nothing() {
+        let mut effects = Effects::new();
+        effects.enabled = false;
+        effects.outcome(&hit(62), "Perfect".to_string(), &layout());
+        effects.outcome(&Outcome::Wrong { note: 60 }, "Wrong".to_string(), &layout());
+        assert_eq!(showing(&effects), (0, 0, 0, false));
+    }
+
+    /// What seeking, looping back and pausing all do to the effects.
+    #[test]
+    fn clearing_takes_everything_off_the_screen() {
+        let mut effects = Effects::new();
+        effects.outcome(&hit(62), "Perfect".to_string(), &layout());
+        effects.outcome(&Outcome::Wrong { note: 60 }, "Wrong".to_string(), &layout());
+        effects.clear();
+        assert_eq!(showing(&effects), (0, 0, 0, false));
+    }
+
+    #[test]
+    fn random_numbers_stay_between_0_and_1() {
+        let mut effects = Effects::new();
+        let numbers: Vec<f32> = (0..1000).map(|_| effects.random()).collect();
+        assert!(numbers.iter().all(|&i| (0.0..=1.0).contains(&i)));
+    }
+}
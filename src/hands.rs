@@ -0,0 +1,304 @@
+use std::vec::Vec;
+
+use crate::practice::CHORD_SPREAD;
+use crate::song::{Part, Tile};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Hand {
+    Left,
+    Right,
+}
+
+/// How tiles get assigned to hands.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum HandSplit {
+    /// Use the parts' names, or give the higher of two parts to the right hand,
+    /// falling back to `Automatic` when the parts don't say.
+    ByPart,
+    /// Guess from pitch and voice leading alone.
+    Automatic,
+}
+
+/// Which hand the player practises, with synthy playing the other.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum HandMode {
+    Both,
+    Left,
+    Right,
+}
+
+impl HandMode {
+    pub fn plays(self, hand: Hand) -> bool {
+        match self {
+            HandMode::Both => true,
+            HandMode::Left => hand == Hand::Left,
+            HandMode::Right => hand == Hand::Right,
+        }
+    }
+}
+
+/// Middle C, as a MIDI key.
+const MIDDLE_C: f32 = 60.0;
+
+/// Widest comfortable stretch for one hand, in semitones.
+const HAND_SPAN: u8 = 12;
+
+fn hand_from_name(name: &str) -> Option<Hand> {
+    let name = name.to_lowercase();
+    let words: Vec<&str> = name
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|i| !i.is_empty())
+        .collect();
+    let has = |w: &str| words.iter().any(|i| *i == w);
+    if has("left") || has("lh") || has("bass") {
+        Some(Hand::Left)
+    } else if has("right") || has("rh") || has("treble") {
+        Some(Hand::Right)
+    } else {
+        None
+    }
+}
+
+/// Assign a hand to every tile. `tiles` must be sorted by start time.
+pub fn assign(tiles: &mut [Tile], parts: &[Part], split: HandSplit) {
+    if split == HandSplit::ByPart && assign_by_part(tiles, parts) {
+        return;
+    }
+    assign_automatic(tiles);
+}
+
+fn assign_by_part(tiles: &mut [Tile], parts: &[Part]) -> bool {
+    let part_of = |tile: &Tile| {
+        parts
+            .iter()
+            .position(|p| p.track == tile.track && p.channel == tile.channel)
+    };
+    let mut used: Vec<usize> = tiles.iter().filter_map(|i| part_of(i)).collect();
+    used.sort();
+    used.dedup();
+
+    let mut hands: Vec<(usize, Hand)> = used
+        .iter()
+        .filter_map(|&i| hand_from_name(&parts[i].name).map(|h| (i, h)))
+        .collect();
+    if hands.len() != used.len() {
+        if used.len() != 2 {
+            return false;
+        }
+        // Two parts with no hints: the higher one is the right hand.
+        let average_note = |part: usize| {
+            let notes: Vec<u8> = tiles
+                .iter()
+                .filter(|i| part_of(*i) == Some(part))
+                .map(|i| i.note)
+                .collect();
+            mean(&notes)
+        };
+        let (low, high) = if average_note(used[0]) < average_note(used[1]) {
+            (used[0], used[1])
+        } else {
+            (used[1], used[0])
+        };
+        hands = vec![(low, Hand::Left), (high, Hand::Right)];
+    }
+    for tile in tiles.iter_mut() {
+        let part = part_of(tile);
+        if let Some(&(_, hand)) = hands.iter().find(|i| Some(i.0) == part) {
+            tile.hand = hand;
+        }
+    }
+    true
+}
+
+/// Split each chord between the hands, keeping each hand close to where it last was.
+fn assign_automatic(tiles: &mut [Tile]) {
+    let mut left = MIDDLE_C - 12.0;
+    let mut right = MIDDLE_C + 7.0;
+    let mut idx = 0;
+    while idx < tiles.len() {
+        let start = tiles[idx].start;
+        let end = tiles[idx..]
+            .iter()
+            .position(|i| i.start > start + CHORD_SPREAD)
+            .map(|i| idx + i)
+            .unwrap_or(tiles.len());
+        let mut chord: Vec<usize> = (idx..end).collect();
+        chord.sort_by_key(|&i| tiles[i].note);
+        let notes: Vec<u8> = chord.iter().map(|&i| tiles[i].note).collect();
+
+        // Notes below `split` go to the left hand.
+        let best = (0..=notes.len())
+            .min_by(|&a, &b| {
+                let cost_a = split_cost(&notes, a, left, right);
+                let cost_b = split_cost(&notes, b, left, right);
+                cost_a.partial_cmp(&cost_b).unwrap()
+            })
+            .unwrap_or(0);
+        for (n, &i) in chord.iter().enumerate() {
+            tiles[i].hand = if n < best { Hand::Left } else { Hand::Right };
+        }
+        if best > 0 {
+            left = mean(&notes[..best]);
+        }
+        if best < notes.len() {
+            right = mean(&notes[best..]);
+        }
+        idx = end;
+    }
+}
+
+fn mean(notes: &[u8]) -> f32 {
+    notes.iter().map(|&i| i as f32).sum::<f32>() / notes.len() as f32
+}
+
+fn split_cost(notes: &[u8], split: usize, left: f32, right: f32) -> f32 {
+    let mut cost = 0.0;
+    let hands = [
+        (Hand::Left, &notes[..split], left),
+        (Hand::Right, &notes[split..], right),
+    ];
+    for &(hand, notes, last) in hands.iter() {
+        if notes.is_empty() {
+            continue;
+        }
+        // Moving the hand far from its last position is awkward...
+        cost += (mean(notes) - last).abs();
+        // ...stretching it past an octave more so...
+        let span = notes[notes.len() - 1] - notes[0];
+        if span > HAND_SPAN {
+            cost += 10.0 * (span - HAND_SPAN) as f32;
+        }
+        // ...and each hand prefers its own side of the keyboard.
+        for &note in notes.iter() {
+            let note = note as f32;
+            cost += match hand {
+                Hand::Left if note > MIDDLE_C + 7.0 => 0.5 * (note - MIDDLE_C - 7.0),
+                Hand::Right if note < MIDDLE_C - 7.0 => 0.5 * (MIDDLE_C - 7.0 - note),
+                _ => 0.0,
+            };
+        }
+    }
+    cost
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::song::PartRole;
+
+    fn part(track: usize, name: &str) -> Part {
+        Part {
+            track,
+            channel: 0,
+            name: name.to_string(),
+            program: 0,
+            note_count: 0,
+            role: PartRole::Play,
+        }
+    }
+
+    fn tile(track: usize, note: u8, start_ms: u64) -> Tile {
+        let mut tile = Tile::test(note, start_ms, 400);
+        tile.track = track;
+        tile
+    }
+
+    fn hands(tiles: &[Tile]) -> Vec<(u8, Hand)> {
+        tiles.iter().map(|i| (i.note, i.hand)).collect()
+    }
+
+    #[test]
+    fn hands_are_read_from_whole_words_of_names() {
+        assert_eq!(hand_from_name("Piano LH"), Some(Hand::Left));
+        assert_eq!(hand_from_name("Right Hand"), Some(Hand::Right));
+        assert_eq!(hand_from_name("bass"), Some(Hand::Left));
+        assert_eq!(hand_from_name("Treble-Clef"), Some(Hand::Right));
+        assert_eq!(hand_from_name("Piano"), None);
+        assert_eq!(hand_from_name("Brighton"), None);
+    }
+
+    #[test]
+    fn named_parts_decide_the_hands() {
+        let parts = [part(0, "Right"), part(1, "Left")];
+        // The left hand is written above the right to show the names win over pitch.
+        let mut tiles = vec![tile(0, 48, 0), tile(1, 72, 0), tile(0, 50, 500)];
+        assign(&mut tiles, &parts, HandSplit::ByPart);
+        assert_eq!(
+            hands(&tiles),
+            vec![(48, Hand::Right), (72, Hand::Left), (50, Hand::Right)]
+        );
+    }
+
+    #[test]
+    fn the_higher_of_two_unnamed_parts_is_the_right_hand() {
+        let parts = [part(0, "Piano"), part(1, "Piano")];
+        let mut tiles = vec![
+            tile(0, 72, 0),
+            tile(1, 40, 0),
+            tile(1, 64, 500),
+            tile(0, 76, 500),
+        ];
+        assign(&mut tiles, &parts, HandSplit::ByPart);
+        assert_eq!(
+            hands(&tiles),
+            vec![
+                (72, Hand::Right),
+                (40, Hand::Left),
+                (64, Hand::Left),
+                (76, Hand::Right)
+            ]
+        );
+    }
+
+    #[test]
+    fn a_single_part_is_split_where_the_hands_are() {
+        let parts = [part(0, "Piano")];
+        // The bass climbs past middle C, and stays with the left hand as it goes.
+        let mut tiles = vec![];
+        for (i, &(low, high)) in [(48, 72), (52, 74), (56, 76), (60, 77), (62, 79), (64, 81)]
+            .iter()
+            .enumerate()
+        {
+            tiles.push(tile(0, high, i as u64 * 500));
+            tiles.push(tile(0, low, i as u64 * 500));
+        }
+        assign(&mut tiles, &parts, HandSplit::ByPart);
+        for pair in tiles.chunks(2) {
+            assert_eq!(pair[0].hand, Hand::Right, "{}", pair[0].note);
+            assert_eq!(pair[1].hand, Hand::Left, "{}", pair[1].note);
+        }
+    }
+
+    #[test]
+    fn single_notes_go_to_the_nearer_hand() {
+        let mut tiles = vec![
+            tile(0, 36, 0),
+            tile(0, 72, 0),
+            tile(0, 62, 500),
+            tile(0, 48, 1000),
+            tile(0, 50, 1000),
+        ];
+        assign(&mut tiles, &[], HandSplit::Automatic);
+        assert_eq!(
+            hands(&tiles),
+            vec![
+                (36, Hand::Left),
+                (72, Hand::Right),
+                (62, Hand::Right),
+                (48, Hand::Left),
+                (50, Hand::Left)
+            ]
+        );
+    }
+
+    #[test]
+    fn splitting_costs_more_the_further_the_hands_move() {
+        let notes = [48, 72];
+        let (left, right) = (48.0, 72.0);
+        assert!(split_cost(&notes, 1, left, right) < split_cost(&notes, 0, left, right));
+        assert!(split_cost(&notes, 1, left, right) < split_cost(&notes, 2, left, right));
+        assert!((split_cost(&notes, 1, left, right)).abs() < 1e-6);
+        // A hand can't stretch two octaves.
+        assert!(split_cost(&notes, 0, left, right) > 100.0);
+    }
+}
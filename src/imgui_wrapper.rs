@@ -145,6 +145,14 @@ impl ImGuiWrapper {
         }
         self.imgui.io_mut().keys_down[key as usize] = false;
     }
+    /// Whether the mouse is over an imgui window.
+    pub fn wants_mouse(&self) -> bool {
+        self.imgui.io().want_capture_mouse
+    }
+    /// Whether imgui is using the keyboard, such as for typing into a text box.
+    pub fn wants_keyboard(&self) -> bool {
+        self.imgui.io().want_capture_keyboard
+    }
     pub fn update_text(&mut self, val: char) {
         self.imgui.io_mut().add_input_character(val);
     }
@@ -0,0 +1,265 @@
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum InputKind {
+    NoteOn {
+        channel: u8,
+        key: u8,
+        vel: u8,
+    },
+    /// Also sent for note-ons with a velocity of zero.
+    NoteOff {
+        channel: u8,
+        key: u8,
+        vel: u8,
+    },
+    Aftertouch {
+        channel: u8,
+        key: u8,
+        pressure: u8,
+    },
+    Control {
+        channel: u8,
+        controller: u8,
+        value: u8,
+    },
+    ProgramChange {
+        channel: u8,
+        program: u8,
+    },
+    ChannelPressure {
+        channel: u8,
+        pressure: u8,
+    },
+    /// Centred on zero, from -8192 to 8191.
+    PitchBend {
+        channel: u8,
+        bend: i16,
+    },
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct InputEvent {
+    /// The moment the event happened, on our own clock, worked out from the device's
+    /// timestamp where there is one.
+    pub time: Instant,
+    pub kind: InputKind,
+}
+
+/// How many data bytes follow a status byte.
+fn data_len(status: u8) -> usize {
+    match status {
+        0x80..=0xBF | 0xE0..=0xEF => 2,
+        0xC0..=0xDF => 1,
+        0xF1 | 0xF3 => 1,
+        0xF2 => 2,
+        _ => 0,
+    }
+}
+
+fn channel_message(status: u8, data: &[u8]) -> Option<InputKind> {
+    let channel = status & 0x0F;
+    let kind = match (status & 0xF0, data) {
+        (0x80, &[key, vel]) => InputKind::NoteOff { channel, key, vel },
+        (0x90, &[key, 0]) => InputKind::NoteOff {
+            channel,
+            key,
+            vel: 0,
+        },
+        (0x90, &[key, vel]) => InputKind::NoteOn { channel, key, vel },
+        (0xA0, &[key, pressure]) => InputKind::Aftertouch {
+            channel,
+            key,
+            pressure,
+        },
+        (0xB0, &[controller, value]) => InputKind::Control {
+            channel,
+            controller,
+            value,
+        },
+        (0xC0, &[program]) => InputKind::ProgramChange { channel, program },
+        (0xD0, &[pressure]) => InputKind::ChannelPressure { channel, pressure },
+        (0xE0, &[lsb, msb]) => InputKind::PitchBend {
+            channel,
+            bend: ((msb as i16) << 7 | lsb as i16) - 8192,
+        },
+        _ => return None,
+    };
+    Some(kind)
+}
+
+/// Turns the raw bytes coming from a MIDI port into channel messages.
+/// Handles running status, skips SysEx and system messages, and drops anything malformed.
+#[derive(Default)]
+pub struct Decoder {
+    running: Option<u8>,
+    in_sysex: bool,
+    /// Data bytes still to be skipped after a system common message.
+    skip: usize,
+    data: Vec<u8>,
+}
+
+impl Decoder {
+    pub fn feed(&mut self, bytes: &[u8]) -> Vec<InputKind> {
+        let mut out = vec![];
+        for &byte in bytes {
+            match byte {
+                // Real-time messages (clock, active sensing...) can appear anywhere
+                // and don't affect running status.
+                0xF8..=0xFF => (),
+                0xF0 => {
+                    self.in_sysex = true;
+                    self.running = None;
+                    self.data.clear();
+                }
+                0xF7 => self.in_sysex = false,
+                0xF1..=0xF6 => {
+                    self.in_sysex = false;
+                    self.running = None;
+                    self.skip = data_len(byte);
+                    self.data.clear();
+                }
+                0x80..=0xEF => {
+                    self.in_sysex = false;
+                    self.running = Some(byte);
+                    self.skip = 0;
+                    self.data.clear();
+                }
+                _ if self.in_sysex => (),
+                _ if self.skip > 0 => self.skip -= 1,
+                _ => {
+                    // Data bytes without a status byte to go with them are dropped.
+                    if let Some(status) = self.running {
+                        self.data.push(byte);
+                        if self.data.len() == data_len(status) {
+                            out.extend(channel_message(status, &self.data));
+                            self.data.clear();
+                        }
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+/// Maps midir's microsecond timestamps onto `Instant`s, keeping the device's
+/// spacing between events while resyncing if the two clocks drift apart.
+#[derive(Default)]
+pub struct StampClock {
+    origin: Option<(u64, Instant)>,
+}
+
+impl StampClock {
+    const MAX_DRIFT: Duration = Duration::from_millis(20);
+
+    pub fn instant(&mut self, stamp: u64) -> Instant {
+        let now = Instant::now();
+        if let Some((base, at)) = self.origin {
+            if stamp >= base {
+                let time = at + Duration::from_micros(stamp - base);
+                if time <= now && now - time < Self::MAX_DRIFT {
+                    return time;
+                }
+            }
+        }
+        self.origin = Some((stamp, now));
+        now
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn running_status_repeats_the_last_status() {
+        let mut decoder = Decoder::default();
+        let kinds = decoder.feed(&[0x91, 60, 100, 64, 90, 60, 0]);
+        assert_eq!(
+            kinds,
+            vec![
+                InputKind::NoteOn {
+                    channel: 1,
+                    key: 60,
+                    vel: 100
+                },
+                InputKind::NoteOn {
+                    channel: 1,
+                    key: 64,
+                    vel: 90
+                },
+                InputKind::NoteOff {
+                    channel: 1,
+                    key: 60,
+                    vel: 0
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn messages_can_be_split_across_packets() {
+        let mut decoder = Decoder::default();
+        assert!(decoder.feed(&[0xB0, 64]).is_empty());
+        assert_eq!(
+            decoder.feed(&[127, 0xE0, 0x00]),
+            vec![InputKind::Control {
+                channel: 0,
+                controller: 64,
+                value: 127
+            }]
+        );
+        assert_eq!(
+            decoder.feed(&[0x40]),
+            vec![InputKind::PitchBend {
+                channel: 0,
+                bend: 0
+            }]
+        );
+    }
+
+    #[test]
+    fn split_sysex_is_skipped() {
+        let mut decoder = Decoder::default();
+        assert_eq!(decoder.feed(&[0x90, 60, 100, 0xF0, 0x7E, 0x7F]).len(), 1);
+        // The rest of the SysEx arrives later, and must not be read as note data.
+        assert!(decoder.feed(&[0x09, 0x01]).is_empty());
+        assert!(decoder.feed(&[0x02, 0xF7, 61, 100]).is_empty());
+        assert_eq!(
+            decoder.feed(&[0x90, 61, 100]),
+            vec![InputKind::NoteOn {
+                channel: 0,
+                key: 61,
+                vel: 100
+            }]
+        );
+    }
+
+    #[test]
+    fn realtime_bytes_do_not_interrupt_messages() {
+        let mut decoder = Decoder::default();
+        assert_eq!(
+            decoder.feed(&[0x80, 0xF8, 60, 0xFE, 40]),
+            vec![InputKind::NoteOff {
+                channel: 0,
+                key: 60,
+                vel: 40
+            }]
+        );
+    }
+
+    #[test]
+    fn system_common_data_is_skipped() {
+        let mut decoder = Decoder::default();
+        // Song position pointer, then data with no status to go with it.
+        assert!(decoder.feed(&[0xF2, 0x10, 0x20, 0x30]).is_empty());
+        assert_eq!(
+            decoder.feed(&[0xC3, 5]),
+            vec![InputKind::ProgramChange {
+                channel: 3,
+                program: 5
+            }]
+        );
+    }
+}
@@ -0,0 +1,908 @@
+use std::time::Duration;
+use std::vec::Vec;
+
+use crate::song::{PedalSpan, Tile};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Judgment {
+    Perfect,
+    Great,
+    Good,
+    Miss,
+}
+
+impl Judgment {
+    pub fn name(self) -> &'static str {
+        match self {
+            Judgment::Perfect => "Perfect",
+            Judgment::Great => "Great",
+            Judgment::Good => "Good",
+            Judgment::Miss => "Miss",
+        }
+    }
+
+    /// How much this judgment counts towards accuracy.
+    fn weight(self) -> f32 {
+        match self {
+            Judgment::Perfect => 1.0,
+            Judgment::Great => 0.7,
+            Judgment::Good => 0.4,
+            Judgment::Miss => 0.0,
+        }
+    }
+}
+
+/// Maximum distance from a tile's start for each judgment.
+#[derive(Debug, Copy, Clone)]
+pub struct Windows {
+    pub perfect: Duration,
+    pub great: Duration,
+    pub good: Duration,
+}
+
+impl Default for Windows {
+    fn default() -> Self {
+        Self {
+            perfect: Duration::from_millis(35),
+            great: Duration::from_millis(75),
+            good: Duration::from_millis(130),
+        }
+    }
+}
+
+impl Windows {
+    pub fn classify(&self, distance: Duration) -> Judgment {
+        if distance <= self.perfect {
+            Judgment::Perfect
+        } else if distance <= self.great {
+            Judgment::Great
+        } else if distance <= self.good {
+            Judgment::Good
+        } else {
+            Judgment::Miss
+        }
+    }
+}
+
+/// Written dynamics, from softest to loudest.
+#[derive(Debug, Copy, Clone, PartialEq, PartialOrd)]
+pub enum Dynamic {
+    Pianissimo,
+    Piano,
+    MezzoPiano,
+    MezzoForte,
+    Forte,
+    Fortissimo,
+}
+
+impl Dynamic {
+    pub fn from_velocity(vel: u8) -> Self {
+        match vel {
+            0..=31 => Dynamic::Pianissimo,
+            32..=47 => Dynamic::Piano,
+            48..=63 => Dynamic::MezzoPiano,
+            64..=79 => Dynamic::MezzoForte,
+            80..=95 => Dynamic::Forte,
+            _ => Dynamic::Fortissimo,
+        }
+    }
+
+    pub fn marking(self) -> &'static str {
+        match self {
+            Dynamic::Pianissimo => "pp",
+            Dynamic::Piano => "p",
+            Dynamic::MezzoPiano => "mp",
+            Dynamic::MezzoForte => "mf",
+            Dynamic::Forte => "f",
+            Dynamic::Fortissimo => "ff",
+        }
+    }
+}
+
+/// How credit for dynamics falls off as the played velocity moves away from the written one.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ToleranceCurve {
+    /// Full credit within the tolerance, then falling linearly to nothing at three times it.
+    Linear,
+    /// A bell curve, with the tolerance as its width.
+    Gaussian,
+    /// Full credit in the same dynamic, half in the next one over.
+    Bucket,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct DynamicsOptions {
+    pub enabled: bool,
+    pub curve: ToleranceCurve,
+    /// In velocity steps.
+    pub tolerance: u8,
+}
+
+impl Default for DynamicsOptions {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            curve: ToleranceCurve::Linear,
+            tolerance: 12,
+        }
+    }
+}
+
+impl DynamicsOptions {
+    /// Credit, from 0 to 1, for playing `played` where `written` was asked for.
+    pub fn credit(&self, played: u8, written: u8) -> f32 {
+        let distance = (played as f32 - written as f32).abs();
+        let tolerance = self.tolerance.max(1) as f32;
+        match self.curve {
+            ToleranceCurve::Linear => {
+                (1.0 - (distance - tolerance).max(0.0) / (2.0 * tolerance)).max(0.0)
+            }
+            ToleranceCurve::Gaussian => (-0.5 * (distance / tolerance).powi(2)).exp(),
+            ToleranceCurve::Bucket => {
+                let played = Dynamic::from_velocity(played) as i32;
+                let written = Dynamic::from_velocity(written) as i32;
+                match (played - written).abs() {
+                    0 => 1.0,
+                    1 => 0.5,
+                    _ => 0.0,
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum Outcome {
+    /// A tile was played. `offset` is in milliseconds, negative when early.
+    Hit {
+        tile: usize,
+        note: u8,
+        judgment: Judgment,
+        offset: f32,
+        /// Played and written velocities.
+        velocity: (u8, u8),
+    },
+    /// A tile scrolled past without being played.
+    Missed { tile: usize, note: u8 },
+    /// A key was pressed with no tile close enough to it.
+    Wrong { note: u8 },
+}
+
+impl Outcome {
+    pub fn judgment(&self) -> Judgment {
+        match self {
+            Outcome::Hit { judgment, .. } => *judgment,
+            _ => Judgment::Miss,
+        }
+    }
+}
+
+/// Compares the player's key presses against a song's tiles.
+pub struct Scoreboard {
+    pub windows: Windows,
+    pub dynamics: DynamicsOptions,
+    dynamics_total: f32,
+    dynamics_count: u32,
+    /// Also judge when the sustain pedal goes down and comes up.
+    pub judge_pedal: bool,
+    pedal_down: bool,
+    pedal_total: f32,
+    pedal_count: u32,
+    /// Whether the pedal going down and coming up has been judged, for each written span.
+    /// `None` after a seek, until it's worked out again from `pedal_from`.
+    pedal_judged: Option<Vec<(bool, bool)>>,
+    /// Written pedal changes before this are skipped rather than missed.
+    pedal_from: Duration,
+    judged: Vec<bool>,
+    // Every tile before this one has been judged.
+    cursor: usize,
+    pub perfect: u32,
+    pub great: u32,
+    pub good: u32,
+    pub miss: u32,
+    pub wrong_notes: u32,
+    pub combo: u32,
+    pub max_combo: u32,
+    offset_total: f32,
+    rate: f32,
+    /// The slowest and fastest playback rates anything was judged at.
+    pub min_rate: f32,
+    pub max_rate: f32,
+    /// The most recent outcome, along with the song time it happened at.
+    pub last: Option<(Outcome, Duration)>,
+}
+
+impl Scoreboard {
+    pub fn new(windows: Windows) -> Self {
+        Self {
+            windows,
+            dynamics: DynamicsOptions::default(),
+            dynamics_total: 0.0,
+            dynamics_count: 0,
+            judge_pedal: false,
+            pedal_down: false,
+            pedal_total: 0.0,
+            pedal_count: 0,
+            pedal_judged: None,
+            pedal_from: Duration::from_secs(0),
+            judged: vec![],
+            cursor: 0,
+            perfect: 0,
+            great: 0,
+            good: 0,
+            miss: 0,
+            wrong_notes: 0,
+            combo: 0,
+            max_combo: 0,
+            offset_total: 0.0,
+            rate: 1.0,
+            min_rate: 1.0,
+            max_rate: 1.0,
+            last: None,
+        }
+    }
+
+    /// Clear all statistics and prepare to judge `tile_count` tiles.
+    pub fn reset(&mut self, tile_count: usize) {
+        let rate = self.rate;
+        let dynamics = self.dynamics;
+        let judge_pedal = self.judge_pedal;
+        *self = Self::new(self.windows);
+        self.dynamics = dynamics;
+        self.judge_pedal = judge_pedal;
+        self.judged = vec![false; tile_count];
+        self.set_rate(rate);
+        self.min_rate = rate;
+        self.max_rate = rate;
+    }
+
+    /// Playback rate to credit judgments with from now on.
+    pub fn set_rate(&mut self, rate: f32) {
+        self.rate = rate;
+    }
+
+    /// Describes the playback speeds of this run, e.g. "75%" or "50-100%".
+    pub fn speed_label(&self) -> String {
+        let (min, max) = (self.min_rate * 100.0, self.max_rate * 100.0);
+        if (max - min).abs() < 0.5 {
+            format!("{:.0}%", min)
+        } else {
+            format!("{:.0}-{:.0}%", min, max)
+        }
+    }
+
+    /// Continue judging from `pos`, forgetting about any tile after it.
+    /// Tiles before it are skipped without counting as misses.
+    pub fn seek(&mut self, tiles: &[Tile], pos: Duration) {
+        let good = self.windows.good;
+        self.judged = tiles.iter().map(|i| i.start + good < pos).collect();
+        self.cursor = self.judged.iter().take_while(|&&i| i).count();
+        self.pedal_from = pos.checked_sub(good).unwrap_or_default();
+        self.pedal_judged = None;
+    }
+
+    /// Skip every tile starting before `pos` without judging it, such as those in a count-in.
+    pub fn skip_before(&mut self, tiles: &[Tile], pos: Duration) {
+        if self.judged.len() != tiles.len() {
+            self.reset(tiles.len());
+        }
+        for (judged, tile) in self.judged.iter_mut().zip(tiles) {
+            if tile.start < pos {
+                *judged = true;
+            }
+        }
+        self.pedal_from = self.pedal_from.max(pos);
+        self.pedal_judged = None;
+    }
+
+    fn record(&mut self, outcome: Outcome, now: Duration) {
+        match outcome {
+            Outcome::Hit {
+                judgment: Judgment::Miss,
+                ..
+            }
+            | Outcome::Missed { .. } => {
+                self.miss += 1;
+                self.combo = 0;
+            }
+            Outcome::Hit {
+                judgment,
+                offset,
+                velocity: (played, written),
+                ..
+            } => {
+                match judgment {
+                    Judgment::Perfect => self.perfect += 1,
+                    Judgment::Great => self.great += 1,
+                    Judgment::Good => self.good += 1,
+                    Judgment::Miss => unreachable!(),
+                }
+                if self.dynamics.enabled {
+                    self.dynamics_total += self.dynamics.credit(played, written);
+                    self.dynamics_count += 1;
+                }
+                self.offset_total += offset;
+                self.combo += 1;
+                self.max_combo = self.max_combo.max(self.combo);
+            }
+            Outcome::Wrong { .. } => {
+                self.wrong_notes += 1;
+                self.combo = 0;
+            }
+        }
+        self.min_rate = self.min_rate.min(self.rate);
+        self.max_rate = self.max_rate.max(self.rate);
+        self.last = Some((outcome, now));
+    }
+
+    /// Judge a note-on for `note` played with velocity `vel` at song time `now`.
+    /// `tiles` must be sorted by start time.
+    pub fn note_on(&mut self, tiles: &[Tile], note: u8, vel: u8, now: Duration) -> Outcome {
+        if self.judged.len() != tiles.len() {
+            self.reset(tiles.len());
+        }
+        let earliest = now.checked_sub(self.windows.good).unwrap_or_default();
+        let mut first = match tiles.binary_search_by_key(&earliest, |i| i.start) {
+            Ok(idx) | Err(idx) => idx,
+        };
+        while first > 0 && tiles[first - 1].start >= earliest {
+            first -= 1;
+        }
+        let mut best: Option<(usize, Duration)> = None;
+        for (idx, tile) in tiles.iter().enumerate().skip(first) {
+            if tile.start > now + self.windows.good {
+                break;
+            }
+            if tile.note != note || self.judged[idx] {
+                continue;
+            }
+            let distance = if tile.start > now {
+                tile.start - now
+            } else {
+                now - tile.start
+            };
+            if best.map(|(_, d)| distance < d).unwrap_or(true) {
+                best = Some((idx, distance));
+            }
+        }
+        let outcome = match best {
+            Some((idx, distance)) => {
+                self.judged[idx] = true;
+                let offset = distance.as_secs_f32() * 1000.0;
+                Outcome::Hit {
+                    tile: idx,
+                    note,
+                    judgment: self.windows.classify(distance),
+                    offset: if tiles[idx].start > now {
+                        -offset
+                    } else {
+                        offset
+                    },
+                    velocity: (vel, tiles[idx].velocity),
+                }
+            }
+            None => Outcome::Wrong { note },
+        };
+        self.record(outcome, now);
+        outcome
+    }
+
+    /// Mark every tile that has scrolled out of its window unplayed as missed.
+    pub fn update(&mut self, tiles: &[Tile], now: Duration) -> Vec<Outcome> {
+        if self.judged.len() != tiles.len() {
+            self.reset(tiles.len());
+        }
+        let mut missed = vec![];
+        while self.cursor < tiles.len() && tiles[self.cursor].start + self.windows.good < now {
+            if !self.judged[self.cursor] {
+                self.judged[self.cursor] = true;
+                let outcome = Outcome::Missed {
+                    tile: self.cursor,
+                    note: tiles[self.cursor].note,
+                };
+                self.record(outcome, now);
+                missed.push(outcome);
+            }
+            self.cursor += 1;
+        }
+        missed
+    }
+
+    /// What to call `outcome` on screen, with the dynamics if they're being judged.
+    pub fn label(&self, outcome: &Outcome) -> String {
+        match *outcome {
+            Outcome::Wrong { .. } => "Wrong".to_string(),
+            Outcome::Hit {
+                velocity: (played, written),
+                ..
+            } if self.dynamics.enabled => format!(
+                "{} ({} / {})",
+                outcome.judgment().name(),
+                Dynamic::from_velocity(played).marking(),
+                Dynamic::from_velocity(written).marking()
+            ),
+            _ => outcome.judgment().name().to_string(),
+        }
+    }
+
+    pub fn judged_count(&self) -> u32 {
+        self.perfect + self.great + self.good + self.miss
+    }
+
+    /// Accuracy over all judged tiles, as a percentage.
+    pub fn accuracy(&self) -> f32 {
+        let total = self.judged_count() + self.wrong_notes;
+        if total == 0 {
+            return 100.0;
+        }
+        let points = self.perfect as f32 * Judgment::Perfect.weight()
+            + self.great as f32 * Judgment::Great.weight()
+            + self.good as f32 * Judgment::Good.weight();
+        100.0 * points / total as f32
+    }
+
+    /// Average hit offset in milliseconds, negative when the player tends to be early.
+    pub fn mean_offset(&self) -> f32 {
+        let hits = self.perfect + self.great + self.good;
+        if hits == 0 {
+            0.0
+        } else {
+            self.offset_total / hits as f32
+        }
+    }
+
+    /// How closely the played dynamics matched the written ones, as a percentage,
+    /// if dynamics were judged at all.
+    pub fn dynamics_accuracy(&self) -> Option<f32> {
+        if self.dynamics_count == 0 {
+            None
+        } else {
+            Some(100.0 * self.dynamics_total / self.dynamics_count as f32)
+        }
+    }
+
+    /// Which of `pedals` have been judged, starting afresh after a seek or if they've changed.
+    fn sync_pedals(&mut self, pedals: &[PedalSpan]) -> &mut Vec<(bool, bool)> {
+        let stale = self
+            .pedal_judged
+            .as_ref()
+            .map(|i| i.len() != pedals.len())
+            .unwrap_or(true);
+        if stale {
+            let from = self.pedal_from;
+            self.pedal_judged = Some(
+                pedals
+                    .iter()
+                    .map(|i| (i.start < from, i.end < from))
+                    .collect(),
+            );
+        }
+        self.pedal_judged.as_mut().unwrap()
+    }
+
+    /// Judge the pedal going down or coming up at `now` against the nearest written
+    /// pedal change of the same kind that hasn't been judged yet. Changes with none close
+    /// enough count as misses. Repeated values that don't change its state are ignored.
+    pub fn pedal(&mut self, down: bool, pedals: &[PedalSpan], now: Duration) -> Option<Judgment> {
+        if down == self.pedal_down {
+            return None;
+        }
+        self.pedal_down = down;
+        if !self.judge_pedal {
+            return None;
+        }
+        let windows = self.windows;
+        let judged = self.sync_pedals(pedals);
+        let distance = |t: Duration| {
+            if t > now {
+                t - now
+            } else {
+                now - t
+            }
+        };
+        let nearest = pedals
+            .iter()
+            .enumerate()
+            .filter(|&(idx, _)| if down { !judged[idx].0 } else { !judged[idx].1 })
+            .map(|(idx, i)| (idx, distance(if down { i.start } else { i.end })))
+            .filter(|&(_, d)| d <= windows.good)
+            .min_by_key(|&(_, d)| d);
+        let judgment = match nearest {
+            Some((idx, d)) => {
+                if down {
+                    judged[idx].0 = true;
+                } else {
+                    judged[idx].1 = true;
+                }
+                windows.classify(d)
+            }
+            None => Judgment::Miss,
+        };
+        self.pedal_total += judgment.weight();
+        self.pedal_count += 1;
+        Some(judgment)
+    }
+
+    /// Count every written pedal change that has passed its window without being played
+    /// as missed.
+    pub fn update_pedals(&mut self, pedals: &[PedalSpan], now: Duration) {
+        if !self.judge_pedal {
+            // Changes from before judging is turned on aren't held against the player.
+            self.pedal_from = now;
+            self.pedal_judged = None;
+            return;
+        }
+        let good = self.windows.good;
+        let judged = self.sync_pedals(pedals);
+        let mut missed = 0;
+        for (span, judged) in pedals.iter().zip(judged.iter_mut()) {
+            if !judged.0 && span.start + good < now {
+                judged.0 = true;
+                missed += 1;
+            }
+            if !judged.1 && span.end + good < now {
+                judged.1 = true;
+                missed += 1;
+            }
+        }
+        self.pedal_count += missed;
+    }
+
+    /// How well the pedalling matched the written pedal markings, as a percentage,
+    /// if the pedal was judged at all.
+    pub fn pedal_accuracy(&self) -> Option<f32> {
+        if self.pedal_count == 0 {
+            None
+        } else {
+            Some(100.0 * self.pedal_total / self.pedal_count as f32)
+        }
+    }
+
+    pub fn grade(&self) -> &'static str {
+        match self.accuracy() {
+            a if a >= 95.0 => "S",
+            a if a >= 90.0 => "A",
+            a if a >= 80.0 => "B",
+            a if a >= 70.0 => "C",
+            a if a >= 60.0 => "D",
+            _ => "F",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-3
+    }
+
+    fn scoreboard(tiles: &[Tile]) -> Scoreboard {
+        let mut board = Scoreboard::new(Windows::default());
+        board.reset(tiles.len());
+        board
+    }
+
+    fn offset(outcome: Outcome) -> f32 {
+        match outcome {
+            Outcome::Hit { offset, .. } => offset,
+            _ => panic!("{:?} is not a hit", outcome),
+        }
+    }
+
+    fn tile_of(outcome: Outcome) -> Option<usize> {
+        match outcome {
+            Outcome::Hit { tile, .. } | Outcome::Missed { tile, .. } => Some(tile),
+            Outcome::Wrong { .. } => None,
+        }
+    }
+
+    #[test]
+    fn windows_classify_by_distance() {
+        let windows = Windows::default();
+        assert_eq!(windows.classify(ms(0)), Judgment::Perfect);
+        assert_eq!(windows.classify(ms(35)), Judgment::Perfect);
+        assert_eq!(windows.classify(ms(36)), Judgment::Great);
+        assert_eq!(windows.classify(ms(75)), Judgment::Great);
+        assert_eq!(windows.classify(ms(76)), Judgment::Good);
+        assert_eq!(windows.classify(ms(130)), Judgment::Good);
+        assert_eq!(windows.classify(ms(131)), Judgment::Miss);
+    }
+
+    #[test]
+    fn hits_the_nearest_unjudged_tile() {
+        let tiles = [Tile::test(60, 1000, 100), Tile::test(60, 1100, 100)];
+        let mut board = scoreboard(&tiles);
+
+        let first = board.note_on(&tiles, 60, 100, ms(1080));
+        assert_eq!(tile_of(first), Some(1));
+        assert_eq!(first.judgment(), Judgment::Perfect);
+        // The tile was still to come, so the hit was early.
+        assert!(close(offset(first), -20.0));
+
+        // The nearer tile is taken, so the next press goes to the other one.
+        let second = board.note_on(&tiles, 60, 100, ms(1080));
+        assert_eq!(tile_of(second), Some(0));
+        assert_eq!(second.judgment(), Judgment::Good);
+        assert!(close(offset(second), 80.0));
+        assert!(close(board.mean_offset(), 30.0));
+
+        let third = board.note_on(&tiles, 60, 100, ms(1080));
+        assert_eq!(tile_of(third), None);
+        assert_eq!(board.wrong_notes, 1);
+    }
+
+    #[test]
+    fn keys_with_no_tile_in_reach_are_wrong() {
+        let tiles = [Tile::test(60, 1000, 100)];
+        let mut board = scoreboard(&tiles);
+        assert_eq!(tile_of(board.note_on(&tiles, 62, 100, ms(1000))), None);
+        assert_eq!(tile_of(board.note_on(&tiles, 60, 100, ms(800))), None);
+        assert_eq!(board.wrong_notes, 2);
+        assert_eq!(board.judged_count(), 0);
+    }
+
+    #[test]
+    fn combo_counts_hits_in_a_row() {
+        let tiles: Vec<Tile> = (0..4).map(|i| Tile::test(60, i * 1000, 100)).collect();
+        let mut board = scoreboard(&tiles);
+        board.note_on(&tiles, 60, 100, ms(0));
+        board.note_on(&tiles, 60, 100, ms(1000));
+        assert_eq!(board.combo, 2);
+        board.note_on(&tiles, 61, 100, ms(1500));
+        assert_eq!(board.combo, 0);
+        board.note_on(&tiles, 60, 100, ms(2000));
+        assert_eq!((board.combo, board.max_combo), (1, 2));
+        board.update(&tiles, ms(3500));
+        assert_eq!((board.combo, board.max_combo), (0, 2));
+    }
+
+    #[test]
+    fn unplayed_tiles_are_missed_once_out_of_reach() {
+        let tiles = [Tile::test(60, 0, 100), Tile::test(64, 500, 100)];
+        let mut board = scoreboard(&tiles);
+        assert!(board.update(&tiles, ms(130)).is_empty());
+        let missed = board.update(&tiles, ms(200));
+        assert_eq!(missed.len(), 1);
+        assert_eq!(tile_of(missed[0]), Some(0));
+        assert!(board.update(&tiles, ms(200)).is_empty());
+        assert_eq!(board.miss, 1);
+    }
+
+    #[test]
+    fn accuracy_and_grade_weigh_each_judgment() {
+        let tiles = [Tile::test(60, 0, 100), Tile::test(62, 1000, 100)];
+        let mut board = scoreboard(&tiles);
+        assert!(close(board.accuracy(), 100.0));
+        board.note_on(&tiles, 60, 100, ms(0));
+        board.note_on(&tiles, 62, 100, ms(1050));
+        assert_eq!((board.perfect, board.great), (1, 1));
+        assert!(close(board.accuracy(), 85.0));
+        assert_eq!(board.grade(), "B");
+        // Wrong notes count against accuracy too.
+        board.note_on(&tiles, 70, 100, ms(1500));
+        assert!(close(board.accuracy(), 1.7 / 3.0 * 100.0));
+        assert_eq!(board.grade(), "F");
+    }
+
+    #[test]
+    fn misjudged_hits_count_as_misses() {
+        let tiles = [Tile::test(60, 0, 100)];
+        let mut board = scoreboard(&tiles);
+        board.note_on(&tiles, 60, 100, ms(0));
+        board.record(
+            Outcome::Hit {
+                tile: 0,
+                note: 60,
+                judgment: Judgment::Miss,
+                offset: 0.0,
+                velocity: (100, 100),
+            },
+            ms(0),
+        );
+        assert_eq!((board.perfect, board.good, board.miss), (1, 0, 1));
+        assert_eq!(board.combo, 0);
+    }
+
+    #[test]
+    fn seeking_skips_earlier_tiles_and_reopens_later_ones() {
+        let tiles: Vec<Tile> = (0..3).map(|i| Tile::test(60, i * 1000, 100)).collect();
+        let mut board = scoreboard(&tiles);
+        board.seek(&tiles, ms(1500));
+        assert!(board.update(&tiles, ms(1600)).is_empty());
+        assert_eq!(board.miss, 0);
+        assert_eq!(tile_of(board.note_on(&tiles, 60, 100, ms(1100))), None);
+
+        board.seek(&tiles, ms(0));
+        assert_eq!(tile_of(board.note_on(&tiles, 60, 100, ms(0))), Some(0));
+        assert_eq!(tile_of(board.note_on(&tiles, 60, 100, ms(1000))), Some(1));
+    }
+
+    #[test]
+    fn skipped_tiles_are_neither_played_nor_missed() {
+        let tiles: Vec<Tile> = (0..3).map(|i| Tile::test(60, i * 500, 100)).collect();
+        let mut board = scoreboard(&tiles);
+        board.skip_before(&tiles, ms(600));
+        assert_eq!(tile_of(board.note_on(&tiles, 60, 100, ms(500))), None);
+        let missed = board.update(&tiles, ms(2000));
+        assert_eq!(missed.len(), 1);
+        assert_eq!(tile_of(missed[0]), Some(2));
+    }
+
+    #[test]
+    fn reset_clears_the_counts_but_keeps_the_rate() {
+        let tiles = [Tile::test(60, 0, 100), Tile::test(60, 1000, 100)];
+        let mut board = scoreboard(&tiles);
+        board.set_rate(0.5);
+        board.note_on(&tiles, 60, 100, ms(0));
+        board.set_rate(1.0);
+        board.note_on(&tiles, 60, 100, ms(1000));
+        assert_eq!(board.speed_label(), "50-100%");
+        assert!(board.last.is_some());
+
+        board.set_rate(0.75);
+        board.reset(tiles.len());
+        assert_eq!(board.judged_count(), 0);
+        assert_eq!(board.max_combo, 0);
+        assert!(board.last.is_none());
+        assert_eq!(board.speed_label(), "75%");
+    }
+
+    fn credits(curve: ToleranceCurve) -> Vec<f32> {
+        let options = DynamicsOptions {
+            enabled: true,
+            curve,
+            tolerance: 12,
+        };
+        // Written mezzo-forte, then played 0, 1, 2 and 3 tolerances louder.
+        [64, 76, 88, 100]
+            .iter()
+            .map(|&played| options.credit(played, 64))
+            .collect()
+    }
+
+    fn assert_credits(actual: Vec<f32>, expected: [f32; 4]) {
+        for (a, e) in actual.iter().zip(expected.iter()) {
+            assert!(close(*a, *e), "{:?} is not {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn linear_credit_falls_off_after_the_tolerance() {
+        assert_credits(credits(ToleranceCurve::Linear), [1.0, 1.0, 0.5, 0.0]);
+    }
+
+    #[test]
+    fn gaussian_credit_is_a_bell_curve() {
+        assert_credits(
+            credits(ToleranceCurve::Gaussian),
+            [1.0, (-0.5f32).exp(), (-2.0f32).exp(), (-4.5f32).exp()],
+        );
+    }
+
+    #[test]
+    fn bucket_credit_goes_by_dynamic_marking() {
+        // mf, mf, f and ff.
+        assert_credits(credits(ToleranceCurve::Bucket), [1.0, 1.0, 0.5, 0.0]);
+    }
+
+    #[test]
+    fn credit_is_the_same_either_side_and_never_negative() {
+        let options = DynamicsOptions::default();
+        assert!(close(options.credit(40, 64), options.credit(88, 64)));
+        assert!(close(options.credit(1, 127), 0.0));
+        // A zero tolerance is treated as one step.
+        let strict = DynamicsOptions {
+            tolerance: 0,
+            ..options
+        };
+        assert!(close(strict.credit(65, 64), 1.0));
+        assert!(close(strict.credit(66, 64), 0.5));
+    }
+
+    #[test]
+    fn dynamics_are_averaged_over_hits_when_enabled() {
+        let tiles = [Tile::test(60, 0, 100), Tile::test(60, 1000, 100)];
+        let mut board = scoreboard(&tiles);
+        board.note_on(&tiles, 60, 40, ms(0));
+        assert_eq!(board.dynamics_accuracy(), None);
+        board.dynamics.enabled = true;
+        board.note_on(&tiles, 60, 124, ms(1000));
+        // Written at 100, played 24 louder: half credit.
+        assert_eq!(board.dynamics_accuracy().map(|i| i.round()), Some(50.0));
+    }
+
+    fn pedal_span(start_ms: u64, end_ms: u64) -> PedalSpan {
+        PedalSpan {
+            start: ms(start_ms),
+            end: ms(end_ms),
+            track: 0,
+            channel: 0,
+        }
+    }
+
+    fn pedal_board() -> Scoreboard {
+        let mut board = scoreboard(&[]);
+        board.judge_pedal = true;
+        board
+    }
+
+    #[test]
+    fn pedal_changes_are_judged_against_the_nearest_written_one() {
+        let pedals = [pedal_span(1000, 2000), pedal_span(3000, 4000)];
+        let mut board = pedal_board();
+        assert_eq!(
+            board.pedal(true, &pedals, ms(1010)),
+            Some(Judgment::Perfect)
+        );
+        // Repeats that don't change anything are ignored.
+        assert_eq!(board.pedal(true, &pedals, ms(1020)), None);
+        assert_eq!(board.pedal(false, &pedals, ms(2100)), Some(Judgment::Good));
+        assert_eq!(board.pedal(true, &pedals, ms(2950)), Some(Judgment::Great));
+        assert!(close(board.pedal_accuracy().unwrap(), 70.0));
+    }
+
+    #[test]
+    fn pedal_changes_far_from_any_written_one_are_missed() {
+        let pedals = [pedal_span(1000, 2000)];
+        let mut board = pedal_board();
+        assert_eq!(board.pedal(true, &pedals, ms(500)), Some(Judgment::Miss));
+        assert_eq!(board.pedal(false, &pedals, ms(600)), Some(Judgment::Miss));
+        // The written change can still be played once the wrong one has been judged.
+        assert_eq!(
+            board.pedal(true, &pedals, ms(1000)),
+            Some(Judgment::Perfect)
+        );
+        // But not twice.
+        board.pedal(false, &pedals, ms(1100));
+        assert_eq!(board.pedal(true, &pedals, ms(1000)), Some(Judgment::Miss));
+    }
+
+    #[test]
+    fn unplayed_pedal_changes_are_missed_once_out_of_reach() {
+        let pedals = [pedal_span(1000, 2000)];
+        let mut board = pedal_board();
+        board.update_pedals(&pedals, ms(1100));
+        assert_eq!(board.pedal_accuracy(), None);
+        board.update_pedals(&pedals, ms(1200));
+        board.update_pedals(&pedals, ms(3000));
+        board.update_pedals(&pedals, ms(3000));
+        assert_eq!(board.pedal_count, 2);
+        assert!(close(board.pedal_accuracy().unwrap(), 0.0));
+    }
+
+    #[test]
+    fn seeking_back_reopens_judged_pedal_changes() {
+        let pedals = [pedal_span(1000, 2000)];
+        let tiles = [Tile::test(60, 0, 100)];
+        let mut board = pedal_board();
+        board.pedal(true, &pedals, ms(1000));
+        board.pedal(false, &pedals, ms(2000));
+        board.seek(&tiles, ms(0));
+        assert_eq!(
+            board.pedal(true, &pedals, ms(1000)),
+            Some(Judgment::Perfect)
+        );
+        // Seeking forward skips the changes before the new position without missing them.
+        board.seek(&tiles, ms(5000));
+        board.update_pedals(&pedals, ms(5000));
+        assert_eq!(board.pedal_count, 3);
+    }
+
+    #[test]
+    fn pedal_changes_are_not_held_against_the_player_until_judged() {
+        let pedals = [pedal_span(1000, 2000), pedal_span(3000, 4000)];
+        let mut board = scoreboard(&[]);
+        assert_eq!(board.pedal(true, &pedals, ms(1000)), None);
+        board.update_pedals(&pedals, ms(2500));
+        board.judge_pedal = true;
+        board.update_pedals(&pedals, ms(5000));
+        // Only the second span's changes came after judging was turned on.
+        assert_eq!(board.pedal_count, 2);
+    }
+}
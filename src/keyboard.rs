@@ -1,120 +1,187 @@
-use midir::MidiInput;
-use midir::MidiInputConnection;
-use std::sync::{Arc, Mutex};
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::time::Instant;
 
-use ggez::graphics::screen_coordinates;
 use ggez::graphics::spritebatch::SpriteBatch;
+use ggez::graphics::{Color, DrawMode, DrawParam, Image, Mesh, Rect};
 use ggez::nalgebra as na;
 
 use crate::assets::Assets;
+use crate::devices::DeviceManager;
+use crate::input::{InputEvent, InputKind};
+use crate::layout::{KeyProportions, KeyRange, KeyRect, PianoLayout};
+use crate::pairing::SUSTAIN_CONTROLLER;
 
 pub type BaseKeyboard = (SpriteBatch, SpriteBatch);
 
-// Hard-coding the layout for now.
-// TODO: be smart about this.
-pub static LAYOUT: &str =
-    "WBWWBWBWWBWBWBWWBWBWWBWBWBWWBWBWWBWBWBWWBWBWWBWBWBWWBWBWWBWBWBWWBWBWWBWBWBWWBWBWWBWBWBWW";
+const NOTE_NAMES: [&str; 12] = [
+    "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+];
 
-#[derive(Copy, Clone)]
-pub enum KeyType {
-    WHITE,
-    BLACK,
-}
-
-#[derive(Copy, Clone)]
-pub struct Key {
-    pub key_type: KeyType,
-    pub offset: ggez::nalgebra::Point2<f32>,
+/// The name of a MIDI key, such as "C4" for middle C.
+pub fn note_name(key: u8) -> String {
+    format!("{}{}", NOTE_NAMES[key as usize % 12], key as i32 / 12 - 1)
 }
 
 pub struct Keyboard {
-    // We need to keep a handle to this, otherwise it'll get dropped and our thread with it.
-    #[allow(dead_code)]
-    conn: Option<MidiInputConnection<()>>,
-    active_keys: Arc<Mutex<[bool; 88]>>,
+    pub devices: DeviceManager,
+    sender: Sender<InputEvent>,
+    events: Receiver<InputEvent>,
+    /// Indexed by MIDI key.
+    active_keys: [bool; 128],
+    pedal: bool,
+    layout: PianoLayout,
+    proportions: KeyProportions,
     assets: Arc<Assets>,
+    /// Reused each frame to draw the keys, then the held ones on top.
+    piano: BaseKeyboard,
     active_sprites: BaseKeyboard,
 }
 
 impl Keyboard {
-    pub fn new(assets: Arc<Assets>) -> Self {
-        let active_keys = Arc::new(Mutex::new([false; 88]));
+    /// Lay the piano out along the bottom of `screen`.
+    /// `config` is where to remember which devices to play from.
+    pub fn new(assets: Arc<Assets>, screen: Rect, config: PathBuf) -> Self {
+        let (sender, events) = channel();
         let active_sprites = (
             SpriteBatch::new(assets.white_key_active.clone()),
             SpriteBatch::new(assets.black_key_active.clone()),
         );
-        match MidiInput::new("synthy reader") {
-            Ok(midi_in) => {
-                let ports = midi_in.ports();
-                let keys = active_keys.clone();
-                let conn = match ports.iter().last() {
-                    Some(port) => {
-                        println!("Connected to {:?}", midi_in.port_name(port));
-                        midi_in
-                            .connect(
-                                port,
-                                "synthy-read-input",
-                                move |_, message, _| {
-                                    let mut data = keys.lock().unwrap();
-                                    data[(message[1] - 21) as usize] = message[0] == 144;
-                                },
-                                (),
-                            )
-                            .ok()
-                    }
-                    None => {
-                        println!("Unable to connect to a keyboard!");
-                        None
+        let piano = (
+            SpriteBatch::new(assets.white_key.clone()),
+            SpriteBatch::new(assets.black_key.clone()),
+        );
+        let proportions = KeyProportions {
+            black_width: assets.black_key.width() as f32 / assets.white_key.width() as f32,
+            black_height: assets.black_key.height() as f32 / assets.white_key.height() as f32,
+        };
+        Self {
+            devices: DeviceManager::new(sender.clone(), config),
+            sender,
+            events,
+            active_keys: [false; 128],
+            pedal: false,
+            layout: PianoLayout::new(KeyRange::FULL, screen, proportions),
+            proportions,
+            assets,
+            piano,
+            active_sprites,
+        }
+    }
+
+    /// Show the keys in `range`.
+    pub fn set_range(&mut self, range: KeyRange) {
+        if range != self.layout.range {
+            self.layout = PianoLayout::new(range, self.layout.screen, self.proportions);
+        }
+    }
+
+    /// Fit the piano to the screen, after it's been resized.
+    pub fn update_layout(&mut self, screen: Rect) {
+        if screen != self.layout.screen {
+            self.layout = PianoLayout::new(self.layout.range, screen, self.proportions);
+        }
+    }
+
+    pub fn layout(&self) -> &PianoLayout {
+        &self.layout
+    }
+
+    /// Somewhere other input sources can send events to, to be handled like the MIDI devices'.
+    pub fn sender(&self) -> Sender<InputEvent> {
+        self.sender.clone()
+    }
+
+    /// Feed an event into the input path as if it came from a MIDI device.
+    pub fn send(&self, kind: InputKind) {
+        let _ = self.sender.send(InputEvent {
+            time: Instant::now(),
+            kind,
+        });
+    }
+
+    /// Which MIDI key is at screen position `(x, y)`.
+    pub fn key_at(&self, x: f32, y: f32) -> Option<u8> {
+        self.layout.key_at(x, y)
+    }
+
+    /// Drain every input event received since the last call, in order,
+    /// updating the held-key state along the way.
+    pub fn poll(&mut self) -> Vec<InputEvent> {
+        self.devices.update();
+        let events: Vec<InputEvent> = self.events.try_iter().collect();
+        for ev in events.iter() {
+            let (key, down) = match ev.kind {
+                InputKind::NoteOn { key, vel, .. } => (key, vel > 0),
+                InputKind::NoteOff { key, .. } => (key, false),
+                InputKind::Control {
+                    controller, value, ..
+                } => {
+                    if controller == SUSTAIN_CONTROLLER {
+                        self.pedal = value >= 64;
                     }
-                };
-                Self {
-                    conn,
-                    active_keys,
-                    assets,
-                    active_sprites,
-                }
-            }
-            Err(e) => {
-                println!("Unable to begin establishing a key: {:?}", e);
-                Self {
-                    conn: None,
-                    active_keys,
-                    assets,
-                    active_sprites,
+                    continue;
                 }
+                _ => continue,
+            };
+            if let Some(state) = self.active_keys.get_mut(key as usize) {
+                *state = down;
             }
         }
+        events
+    }
+
+    pub fn pedal(&self) -> bool {
+        self.pedal
     }
 
-    pub fn draw_piano<T: Into<ggez::graphics::DrawParam>>(
-        &mut self,
-        ctx: &mut ggez::Context,
-        params: T,
-    ) {
+    pub fn draw_piano(&mut self, ctx: &mut ggez::Context) {
         let assets = &self.assets;
-        let rect = screen_coordinates(ctx);
-        let width = assets.white_key.width() * 52;
-        let height = assets.white_key.height() as f32;
-        let (white, black) = &assets.keyboard;
+        let active_keys = &self.active_keys;
+        let (white, black) = &mut self.piano;
         let (wa, ba) = &mut self.active_sprites;
+        white.clear();
+        black.clear();
         wa.clear();
         ba.clear();
-        let keys = self.active_keys.lock().unwrap();
-        for i in 0..88 {
-            if keys[i] {
-                let props: Key = assets.keymap[i];
-                let c = (props.offset,);
-                match props.key_type {
-                    KeyType::WHITE => wa.add(c),
-                    KeyType::BLACK => ba.add(c),
-                };
+        let place = |key: &KeyRect, image: &Image| {
+            DrawParam::new()
+                .dest(na::Point2::new(key.rect.x, key.rect.y))
+                .scale(na::Vector2::new(
+                    key.rect.w / image.width() as f32,
+                    key.rect.h / image.height() as f32,
+                ))
+        };
+        for key in self.layout.keys() {
+            let held = active_keys[key.note as usize];
+            if key.black {
+                black.add(place(key, &assets.black_key));
+                if held {
+                    ba.add(place(key, &assets.black_key_active));
+                }
+            } else {
+                white.add(place(key, &assets.white_key));
+                if held {
+                    wa.add(place(key, &assets.white_key_active));
+                }
             }
         }
-        let mut p: ggez::graphics::DrawParam = params.into();
-        p.scale = na::Vector2::new(rect.w / (width as f32), rect.h * 0.15 / height).into();
-        ggez::graphics::draw(ctx, white, p.clone()).unwrap();
-        ggez::graphics::draw(ctx, wa, p.clone()).unwrap();
-        ggez::graphics::draw(ctx, black, p.clone()).unwrap();
-        ggez::graphics::draw(ctx, ba, p).unwrap();
+        ggez::graphics::draw(ctx, white, DrawParam::default()).unwrap();
+        ggez::graphics::draw(ctx, wa, DrawParam::default()).unwrap();
+        ggez::graphics::draw(ctx, black, DrawParam::default()).unwrap();
+        ggez::graphics::draw(ctx, ba, DrawParam::default()).unwrap();
+
+        // Sustain pedal indicator along the bottom edge of the piano.
+        let piano = self.layout.piano_rect();
+        let bar_height = (piano.h * 0.04).max(3.0);
+        let bar = Rect::new(piano.x, piano.y + piano.h - bar_height, piano.w, bar_height);
+        let color = if self.pedal {
+            Color::new(1.0, 0.85, 0.2, 1.0)
+        } else {
+            Color::new(0.2, 0.2, 0.2, 1.0)
+        };
+        let mesh = Mesh::new_rectangle(ctx, DrawMode::fill(), bar, color).unwrap();
+        ggez::graphics::draw(ctx, &mesh, DrawParam::default()).unwrap();
     }
 }
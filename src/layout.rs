@@ -0,0 +1,300 @@
+use ggez::graphics::Rect;
+use std::vec::Vec;
+
+/// How much of the screen's height the piano takes up.
+pub const PIANO_HEIGHT: f32 = 0.15;
+
+pub fn is_black(key: u8) -> bool {
+    matches!(key % 12, 1 | 3 | 6 | 8 | 10)
+}
+
+/// The MIDI keys shown on the piano, inclusive at both ends.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct KeyRange {
+    pub low: u8,
+    pub high: u8,
+}
+
+impl KeyRange {
+    /// A full-size piano, A0 to C8.
+    pub const FULL: KeyRange = KeyRange { low: 21, high: 108 };
+
+    /// Common keyboard sizes, by number of keys.
+    pub const PRESETS: [(u32, KeyRange); 4] = [
+        (49, KeyRange { low: 36, high: 84 }),
+        (61, KeyRange { low: 36, high: 96 }),
+        (76, KeyRange { low: 28, high: 103 }),
+        (88, KeyRange::FULL),
+    ];
+
+    /// The smallest range, of at least two octaves, covering every one of `notes`.
+    /// Both ends land on white keys so the piano doesn't start or end with half a key.
+    pub fn fit<I: IntoIterator<Item = u8>>(notes: I) -> Self {
+        let (mut low, mut high) = notes
+            .into_iter()
+            .fold((u8::MAX, 0), |(low, high), n| (low.min(n), high.max(n)));
+        if low > high {
+            return Self::FULL;
+        }
+        // Widen around the middle until there's room to see where we are.
+        while high - low < 24 {
+            low = low.saturating_sub(1);
+            high = high.saturating_add(1);
+        }
+        // Slide back onto the keyboard rather than showing less of it near the ends.
+        if low < Self::FULL.low {
+            high = high.saturating_add(Self::FULL.low - low);
+            low = Self::FULL.low;
+        }
+        if high > Self::FULL.high {
+            low = low
+                .saturating_sub(high - Self::FULL.high)
+                .max(Self::FULL.low);
+            high = Self::FULL.high;
+        }
+        while is_black(low) {
+            low -= 1;
+        }
+        while is_black(high) {
+            high += 1;
+        }
+        Self { low, high }
+    }
+
+    pub fn contains(&self, key: u8) -> bool {
+        key >= self.low && key <= self.high
+    }
+
+    pub fn key_count(&self) -> u32 {
+        (self.high - self.low) as u32 + 1
+    }
+
+    pub fn keys(&self) -> std::ops::RangeInclusive<u8> {
+        self.low..=self.high
+    }
+
+    pub fn white_count(&self) -> u32 {
+        self.keys().filter(|&k| !is_black(k)).count() as u32
+    }
+}
+
+/// The size of a black key relative to a white one.
+#[derive(Debug, Copy, Clone)]
+pub struct KeyProportions {
+    pub black_width: f32,
+    pub black_height: f32,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct KeyRect {
+    pub note: u8,
+    pub black: bool,
+    pub rect: Rect,
+}
+
+/// Where every key of the piano goes on screen. This is plain arithmetic,
+/// so it can be worked out without a graphics context.
+#[derive(Debug, Clone)]
+pub struct PianoLayout {
+    pub range: KeyRange,
+    /// The area the whole screen covers.
+    pub screen: Rect,
+    /// Indexed by `note - range.low`.
+    keys: Vec<KeyRect>,
+}
+
+impl PianoLayout {
+    /// Lay out the keys in `range` along the bottom of `screen`, filling its width.
+    pub fn new(range: KeyRange, screen: Rect, proportions: KeyProportions) -> Self {
+        let height = screen.h * PIANO_HEIGHT;
+        let top = screen.y + screen.h - height;
+        let white_width = screen.w / range.white_count().max(1) as f32;
+        let black_width = white_width * proportions.black_width;
+        let mut x = screen.x;
+        let mut keys = Vec::with_capacity(range.key_count() as usize);
+        for note in range.keys() {
+            if is_black(note) {
+                // Black keys straddle the gap between the white keys either side.
+                keys.push(KeyRect {
+                    note,
+                    black: true,
+                    rect: Rect::new(
+                        x - black_width / 2.0,
+                        top,
+                        black_width,
+                        height * proportions.black_height,
+                    ),
+                });
+            } else {
+                keys.push(KeyRect {
+                    note,
+                    black: false,
+                    rect: Rect::new(x, top, white_width, height),
+                });
+                x += white_width;
+            }
+        }
+        Self {
+            range,
+            screen,
+            keys,
+        }
+    }
+
+    /// The y coordinate of the top of the piano, where falling tiles are due.
+    pub fn keyboard_line(&self) -> f32 {
+        self.screen.y + self.screen.h * (1.0 - PIANO_HEIGHT)
+    }
+
+    /// The area covered by the whole piano.
+    pub fn piano_rect(&self) -> Rect {
+        let top = self.keyboard_line();
+        Rect::new(
+            self.screen.x,
+            top,
+            self.screen.w,
+            self.screen.y + self.screen.h - top,
+        )
+    }
+
+    pub fn keys(&self) -> &[KeyRect] {
+        &self.keys
+    }
+
+    /// The key for MIDI key `note`, if it's on the piano.
+    pub fn key(&self, note: u8) -> Option<&KeyRect> {
+        if self.range.contains(note) {
+            self.keys.get((note - self.range.low) as usize)
+        } else {
+            None
+        }
+    }
+
+    /// The left edge and width of the key for `note`, for lining things up above it.
+    pub fn note_x(&self, note: u8) -> Option<(f32, f32)> {
+        self.key(note).map(|k| (k.rect.x, k.rect.w))
+    }
+
+    /// Which MIDI key is at screen position `(x, y)`.
+    pub fn key_at(&self, x: f32, y: f32) -> Option<u8> {
+        let hit = |k: &&KeyRect| {
+            x >= k.rect.x && x < k.rect.x + k.rect.w && y >= k.rect.y && y < k.rect.y + k.rect.h
+        };
+        // Black keys sit on top of the white ones, so they get first pick.
+        self.keys
+            .iter()
+            .filter(|k| k.black)
+            .find(hit)
+            .or_else(|| self.keys.iter().filter(|k| !k.black).find(hit))
+            .map(|k| k.note)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROPORTIONS: KeyProportions = KeyProportions {
+        black_width: 0.5,
+        black_height: 0.6,
+    };
+
+    fn assert_rect(rect: Rect, (x, y, w, h): (f32, f32, f32, f32)) {
+        let close = |a: f32, b: f32| (a - b).abs() < 1e-3;
+        assert!(
+            close(rect.x, x) && close(rect.y, y) && close(rect.w, w) && close(rect.h, h),
+            "{:?} is not at {:?}",
+            rect,
+            (x, y, w, h)
+        );
+    }
+
+    /// C4 to E4 on a 300x1000 screen, so white keys are 100 wide and the piano is 150 high.
+    fn small() -> PianoLayout {
+        PianoLayout::new(
+            KeyRange { low: 60, high: 64 },
+            Rect::new(0.0, 0.0, 300.0, 1000.0),
+            PROPORTIONS,
+        )
+    }
+
+    #[test]
+    fn white_keys_fill_the_width() {
+        let layout = small();
+        assert_eq!(layout.keys().len(), 5);
+        assert_rect(layout.key(60).unwrap().rect, (0.0, 850.0, 100.0, 150.0));
+        assert_rect(layout.key(62).unwrap().rect, (100.0, 850.0, 100.0, 150.0));
+        assert_rect(layout.key(64).unwrap().rect, (200.0, 850.0, 100.0, 150.0));
+        assert!((layout.keyboard_line() - 850.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn black_keys_straddle_the_gaps() {
+        let layout = small();
+        let key = layout.key(61).unwrap();
+        assert!(key.black);
+        assert_rect(key.rect, (75.0, 850.0, 50.0, 90.0));
+        assert_rect(layout.key(63).unwrap().rect, (175.0, 850.0, 50.0, 90.0));
+        assert_eq!(layout.note_x(63), Some((175.0, 50.0)));
+    }
+
+    #[test]
+    fn edge_notes_reach_the_screen_edges() {
+        let layout = PianoLayout::new(
+            KeyRange::FULL,
+            Rect::new(100.0, 50.0, 520.0, 400.0),
+            PROPORTIONS,
+        );
+        // 52 white keys, each 10 wide.
+        assert_eq!(layout.keys().len(), 88);
+        assert_rect(layout.key(21).unwrap().rect, (100.0, 390.0, 10.0, 60.0));
+        assert_rect(layout.key(108).unwrap().rect, (610.0, 390.0, 10.0, 60.0));
+    }
+
+    #[test]
+    fn notes_outside_the_range_have_no_key() {
+        let layout = small();
+        assert!(layout.key(59).is_none());
+        assert!(layout.key(65).is_none());
+        assert_eq!(layout.note_x(0), None);
+        assert_eq!(layout.note_x(127), None);
+    }
+
+    #[test]
+    fn black_keys_are_hit_before_the_white_keys_under_them() {
+        let layout = small();
+        assert_eq!(layout.key_at(80.0, 860.0), Some(61));
+        // Below the black key, the white key underneath is hit instead.
+        assert_eq!(layout.key_at(80.0, 960.0), Some(60));
+        assert_eq!(layout.key_at(10.0, 860.0), Some(60));
+        assert_eq!(layout.key_at(299.0, 999.0), Some(64));
+    }
+
+    #[test]
+    fn nothing_is_hit_off_the_piano() {
+        let layout = small();
+        assert_eq!(layout.key_at(50.0, 800.0), None);
+        assert_eq!(layout.key_at(300.0, 900.0), None);
+        assert_eq!(layout.key_at(-1.0, 900.0), None);
+    }
+
+    #[test]
+    fn fit_covers_the_notes_with_two_octaves_at_least() {
+        assert_eq!(KeyRange::fit(vec![60, 64]), KeyRange { low: 50, high: 74 });
+        // Ends on black keys are moved out to the next white key.
+        assert_eq!(KeyRange::fit(vec![60, 61]), KeyRange { low: 48, high: 74 });
+        let wide = KeyRange::fit(vec![30, 100]);
+        assert_eq!(wide, KeyRange { low: 29, high: 100 });
+        assert!(wide.contains(30) && wide.contains(100));
+    }
+
+    #[test]
+    fn fit_stays_on_the_piano() {
+        assert_eq!(KeyRange::fit(vec![]), KeyRange::FULL);
+        assert_eq!(KeyRange::fit(vec![0, 127]), KeyRange::FULL);
+        // Notes at the ends still get two octaves, shifted back onto the keyboard.
+        assert_eq!(KeyRange::fit(vec![21]), KeyRange { low: 21, high: 45 });
+        assert_eq!(KeyRange::fit(vec![0]), KeyRange { low: 21, high: 45 });
+        assert_eq!(KeyRange::fit(vec![108]), KeyRange { low: 84, high: 108 });
+    }
+}
@@ -6,29 +6,80 @@ use crate::imgui_wrapper::ImGuiWrapper;
 use ggez::conf;
 use ggez::event::{self, EventHandler, KeyCode, KeyMods, MouseButton};
 use ggez::graphics;
-use ggez::graphics::DrawParam;
 use ggez::nalgebra as na;
 use ggez::{Context, GameResult};
 use imgui::im_str;
 use std::convert::TryInto;
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::Duration;
 
 mod assets;
+mod audio;
+mod clock;
+mod devices;
+mod effects;
+mod hands;
 //mod event_mgr;
+mod input;
+mod judge;
 mod keyboard;
+mod layout;
 mod midi_interpreter;
+mod midi_out;
+mod pairing;
+mod practice;
+mod qwerty;
 mod song;
+mod synth;
+mod tile_renderer;
+mod timing;
+mod transform;
 
-use keyboard::{Key, KeyType};
+use layout::KeyRange;
+
+/// An integer input box editing a `Duration` in milliseconds.
+fn input_millis(ui: &imgui::Ui, label: &imgui::ImStr, value: &mut Duration) {
+    let mut ms = value.as_millis() as i32;
+    if ui.input_int(label, &mut ms).build() {
+        *value = Duration::from_millis(ms.max(0) as u64);
+    }
+}
 
 struct MainState {
     imgui_wrapper: ImGuiWrapper,
     hidpi_factor: f32,
     main_assets: Arc<assets::Assets>,
     board: keyboard::Keyboard,
+    qwerty: qwerty::QwertyPiano,
+    /// Whether the left button went down on the piano and is still held.
+    mouse_down: bool,
+    /// The key being held down with the mouse, as a MIDI key.
+    mouse_key: Option<u8>,
+    /// How many keys the player's keyboard has, or `None` to fit the piano to the song.
+    key_count: Option<u32>,
+    /// Transform settings being previewed, before they're applied to the song.
+    transform: transform::Transform,
+    /// Only tiles for this MIDI key are highlighted.
+    note_filter: Option<u8>,
     current_song: Option<song::Song>,
-    reference: Instant,
+    scoreboard: judge::Scoreboard,
+    audio: Option<audio::AudioEngine>,
+    sequencer: audio::Sequencer,
+    tile_renderer: tile_renderer::TileRenderer,
+    effects: effects::Effects,
+    midi_out: midi_out::MidiOut,
+    midi_sequencer: audio::Sequencer,
+    clock: clock::SongClock,
+    wait_mode: practice::WaitMode,
+    loop_region: practice::LoopRegion,
+    loop_measures: (i32, i32),
+    goto_measure: i32,
+    /// The note to assign a computer key to, in semitones above the bottom row's C.
+    remap_note: i32,
+    show_parts: bool,
+    show_devices: bool,
+    load_error: Option<String>,
+    pairing: pairing::PairingOptions,
     show_ui: bool,
 }
 
@@ -36,26 +87,221 @@ impl MainState {
     fn new(mut ctx: &mut Context, hidpi_factor: f32) -> GameResult<MainState> {
         let imgui_wrapper = ImGuiWrapper::new(&mut ctx);
         let main_assets = Arc::new(assets::Assets::new(ctx, &std::path::Path::new("assets")));
-        let board = keyboard::Keyboard::new(main_assets.clone());
-        let reference = Instant::now();
+        let devices = ggez::filesystem::user_config_dir(ctx).join("devices.txt");
+        let screen = graphics::screen_coordinates(ctx);
+        let board = keyboard::Keyboard::new(main_assets.clone(), screen, devices);
         let s = MainState {
             imgui_wrapper,
             hidpi_factor,
             main_assets,
+            qwerty: qwerty::QwertyPiano::new(board.sender()),
             board,
+            mouse_down: false,
+            mouse_key: None,
+            key_count: None,
+            transform: transform::Transform::default(),
+            note_filter: None,
             current_song: None,
-            reference,
+            scoreboard: judge::Scoreboard::new(judge::Windows::default()),
+            audio: audio::AudioEngine::new(),
+            sequencer: audio::Sequencer::default(),
+            tile_renderer: tile_renderer::TileRenderer::new(),
+            effects: effects::Effects::new(),
+            midi_out: midi_out::MidiOut::new(),
+            midi_sequencer: audio::Sequencer::default(),
+            clock: clock::SongClock::new(),
+            wait_mode: practice::WaitMode::new(),
+            loop_region: practice::LoopRegion::new(),
+            loop_measures: (1, 4),
+            goto_measure: 1,
+            remap_note: 0,
+            show_parts: false,
+            show_devices: false,
+            load_error: None,
+            pairing: pairing::PairingOptions::default(),
             show_ui: true,
         };
         Ok(s)
     }
+
+    /// Move the song clock to `pos` and bring everything following it up to date.
+    fn seek(&mut self, pos: Duration) {
+        self.clock.seek(pos);
+        if let Some(song) = &mut self.current_song {
+            song.seek(&self.clock);
+            self.sequencer.seek(song, pos, self.audio.as_mut());
+            self.midi_sequencer
+                .seek(song, pos, Some(&mut self.midi_out));
+            self.scoreboard.seek(&song.tiles, pos);
+            if self.loop_region.in_count_in(pos) {
+                self.scoreboard
+                    .skip_before(&song.tiles, self.loop_region.start);
+            }
+        }
+        self.wait_mode.seek(pos);
+        self.effects.clear();
+    }
+
+    /// Start judging the current song's tiles afresh from `pos`,
+    /// after a new song is loaded or its tiles change.
+    fn reload_tiles(&mut self, pos: Duration) {
+        if let Some(song) = &self.current_song {
+            self.scoreboard.reset(song.tiles.len());
+            self.wait_mode.load(&song.tiles);
+        }
+        self.update_range();
+        self.seek(pos);
+    }
+
+    fn preset_range(&self) -> Option<KeyRange> {
+        KeyRange::PRESETS
+            .iter()
+            .find(|i| Some(i.0) == self.key_count)
+            .map(|i| i.1)
+    }
+
+    /// Show the keys of the chosen keyboard size, or just enough for the current song.
+    fn update_range(&mut self) {
+        let range = match (self.preset_range(), &self.current_song) {
+            (Some(range), _) => range,
+            (None, Some(song)) => KeyRange::fit(song.tiles.iter().map(|i| i.note)),
+            (None, None) => KeyRange::FULL,
+        };
+        self.board.set_range(range);
+    }
+
+    /// Jump back to the start of the loop region once playback passes its end.
+    fn check_loop(&mut self) {
+        let (restart, len) = match &self.current_song {
+            Some(song) if self.loop_region.should_wrap(self.clock.position()) => {
+                // Tiles near the end may still be in reach, so miss them now
+                // before deciding whether the pass was clean.
+                let end = self.loop_region.end + self.scoreboard.windows.good;
+                self.scoreboard.update(&song.tiles, end);
+                (
+                    self.loop_region.restart_point(&song.tempo_map),
+                    song.tiles.len(),
+                )
+            }
+            _ => return,
+        };
+        let clean = self.scoreboard.miss == 0 && self.scoreboard.wrong_notes == 0;
+        let rate = self.loop_region.finish_pass(clean, self.clock.rate());
+        self.clock.set_rate(rate);
+        self.scoreboard.set_rate(rate);
+        self.scoreboard.reset(len);
+        self.seek(restart);
+    }
+
+    /// Play the piano key under the mouse, letting go of the previous one.
+    fn press_mouse_key(&mut self, x: f32, y: f32) {
+        let key = self.board.key_at(x, y);
+        if key == self.mouse_key {
+            return;
+        }
+        self.release_mouse_key();
+        if let Some(key) = key {
+            self.board.send(input::InputKind::NoteOn {
+                channel: 0,
+                key,
+                vel: 80,
+            });
+            self.mouse_key = Some(key);
+        }
+    }
+
+    fn release_mouse_key(&mut self) {
+        if let Some(key) = self.mouse_key.take() {
+            self.board.send(input::InputKind::NoteOff {
+                channel: 0,
+                key,
+                vel: 0,
+            });
+        }
+    }
+
+    fn toggle_pause(&mut self) {
+        if self.clock.is_playing() {
+            self.clock.pause();
+            self.effects.clear();
+            if let Some(song) = &self.current_song {
+                let pos = self.clock.position();
+                self.sequencer.seek(song, pos, self.audio.as_mut());
+                self.midi_sequencer
+                    .seek(song, pos, Some(&mut self.midi_out));
+            }
+        } else {
+            self.clock.resume();
+        }
+    }
 }
 
 impl EventHandler for MainState {
     fn update(&mut self, _ctx: &mut Context) -> GameResult<()> {
-        //if let Some(song) = self.song.update(&self.reference);
-        let reference = &self.reference;
-        self.current_song.as_mut().map(|i| i.update(reference));
+        let events = self.board.poll();
+        for ev in events.iter() {
+            if let input::InputKind::NoteOn { key, vel, .. } = ev.kind {
+                if vel > 0 {
+                    let at = self.clock.position_at(ev.time);
+                    self.wait_mode.note_on(key, at);
+                }
+            }
+        }
+        self.wait_mode.update(&mut self.clock);
+        let clock = &self.clock;
+        self.current_song.as_mut().map(|i| i.update(clock));
+        if let Some(engine) = self.audio.as_ref().filter(|e| e.play_input) {
+            for ev in events.iter() {
+                match ev.kind {
+                    input::InputKind::NoteOn { key, vel, .. } => {
+                        engine.send(synth::SynthCommand::NoteOn { key, vel })
+                    }
+                    input::InputKind::NoteOff { key, .. } => {
+                        engine.send(synth::SynthCommand::NoteOff { key })
+                    }
+                    _ => (),
+                }
+            }
+        }
+        if let Some(song) = &self.current_song {
+            let pos = self.clock.position();
+            if let Some(engine) = &mut self.audio {
+                self.sequencer.update(song, pos, engine);
+            }
+            self.midi_out.sync(&self.clock);
+            let ahead = midi_out::MidiOut::horizon(&self.clock);
+            self.midi_sequencer.update(song, ahead, &mut self.midi_out);
+        }
+        let mut outcomes = vec![];
+        if let Some(song) = &self.current_song {
+            for ev in events {
+                let at = self.clock.position_at(ev.time);
+                if self.loop_region.in_count_in(at) {
+                    continue;
+                }
+                match ev.kind {
+                    input::InputKind::NoteOn { key, vel, .. } if vel > 0 => {
+                        let outcome = self.scoreboard.note_on(&song.tiles, key, vel, at);
+                        outcomes.push(outcome);
+                    }
+                    input::InputKind::Control {
+                        controller, value, ..
+                    } if controller == pairing::SUSTAIN_CONTROLLER => {
+                        self.scoreboard.pedal(value >= 64, &song.pedals, at);
+                    }
+                    _ => (),
+                }
+            }
+            outcomes.extend(self.scoreboard.update(&song.tiles, self.clock.position()));
+            self.scoreboard
+                .update_pedals(&song.pedals, self.clock.position());
+        }
+        for outcome in outcomes.iter() {
+            let label = self.scoreboard.label(outcome);
+            self.effects.outcome(outcome, label, self.board.layout());
+        }
+        // After this frame's input is judged, so it counts towards the pass.
+        self.check_loop();
         Ok(())
     }
 
@@ -73,47 +319,93 @@ impl EventHandler for MainState {
         // Render game stuff
         {
             let rect = ggez::graphics::screen_coordinates(ctx);
-            let hfac = rect.h * 0.85;
+            self.board.update_layout(rect);
+            let layout = self.board.layout();
+            let hfac = layout.keyboard_line();
 
-            let width_scale = rect.w / (self.main_assets.white_key.width() as f32 * 52f32);
-            let keymap = &self.main_assets.keymap;
             if let Some(song) = &self.current_song {
-                for tile in song.active_tiles.iter() {
-                    let key: Key = keymap[tile.note as usize];
-                    let fac = tile.vertical_height(hfac) / self.main_assets.white_key.height() as f32;
-                    let dest = na::Point2::new(
-                        key.offset.x * width_scale, //TODO: clean this up
-                        tile.vertical_position(&self.reference, rect.h),
-                    );
+                self.tile_renderer
+                    .draw(ctx, song, &self.clock, layout, self.note_filter)?;
+            }
+
+            self.board.draw_piano(ctx);
+
+            // Effects go over the piano, so the struck keys can glow.
+            if self.effects.enabled {
+                self.effects
+                    .draw(ctx, self.board.layout(), self.scoreboard.combo)?;
+            } else if let Some((outcome, at)) = self.scoreboard.last {
+                if self.clock.position() < at + Duration::from_millis(500) {
+                    let color = effects::judgment_color(outcome.judgment());
+                    let mut label = self.scoreboard.label(&outcome);
+                    if let judge::Outcome::Hit { .. } = outcome {
+                        if self.scoreboard.combo > 1 {
+                            label = format!("{} x{}", label, self.scoreboard.combo);
+                        }
+                    }
                     graphics::draw(
                         ctx,
-                        match key.key_type {
-                            KeyType::WHITE => &self.main_assets.white_key,
-                            KeyType::BLACK => &self.main_assets.black_key,
-                        },
-                        DrawParam::new()
-                            .dest(dest)
-                            .scale(na::Vector2::new(width_scale, fac)),
+                        &graphics::Text::new(label),
+                        (na::Point2::new(rect.w / 2.0 - 30.0, hfac - 40.0), color),
                     )
                     .unwrap();
                 }
             }
 
-            self.board.draw_piano(
-                ctx,
-                (
-                    na::Point2::new(0.0, hfac),
-                    graphics::Color::new(1.0, 1.0, 1.0, 0.0),
-                ),
-            );
+            // Count the player in to the loop, beat by beat.
+            if let Some(song) = &self.current_song {
+                let pos = self.clock.position();
+                if let Some(beats) = self.loop_region.beats_left(&song.tempo_map, pos) {
+                    let text = graphics::Text::new(
+                        graphics::TextFragment::new(beats.to_string())
+                            .scale(graphics::Scale::uniform(96.0)),
+                    );
+                    let (w, h) = text.dimensions(ctx);
+                    let dest = na::Point2::new((rect.w - w as f32) / 2.0, (hfac - h as f32) / 2.0);
+                    graphics::draw(ctx, &text, (dest, graphics::WHITE))?;
+                }
+            }
         }
 
         // Render game ui
         if self.show_ui {
             let fps = ggez::timer::fps(ctx);
             let i: i32 = song::deltat().as_millis().try_into().unwrap();
-            let rt = &mut self.reference;
             let song = &mut self.current_song;
+            let clock = &self.clock;
+            let goto_measure = &mut self.goto_measure;
+            let wait_mode = &mut self.wait_mode;
+            let loop_region = &mut self.loop_region;
+            let loop_measures = &mut self.loop_measures;
+            let show_parts = &mut self.show_parts;
+            let show_devices = &mut self.show_devices;
+            let devices = &mut self.board.devices;
+            let midi_out = &mut self.midi_out;
+            let qwerty = &mut self.qwerty;
+            let remap_note = &mut self.remap_note;
+            let note_filter = &mut self.note_filter;
+            let color_mode = &mut self.tile_renderer.color_mode;
+            let effects = &mut self.effects;
+            let fit_range = self.preset_range().unwrap_or(KeyRange::FULL);
+            let key_count = &mut self.key_count;
+            let transform = &mut self.transform;
+            let mut range_changed = false;
+            let new_range = &mut range_changed;
+            let load_error = &mut self.load_error;
+            let pairing = &mut self.pairing;
+            let mut loaded = false;
+            let mut parts_changed = false;
+            let song_loaded = &mut loaded;
+            let roles_changed = &mut parts_changed;
+            let mut seek: Option<Duration> = None;
+            let mut toggle_pause = false;
+            let seek_to = &mut seek;
+            let pause = &mut toggle_pause;
+            let mut rate = self.clock.rate();
+            let new_rate = &mut rate;
+            let score = &mut self.scoreboard;
+            let engine = &mut self.audio;
+            let sequencer = &mut self.sequencer;
             self.imgui_wrapper
                 .render(ctx, self.hidpi_factor, move |ui| {
                     imgui::Window::new(im_str!("Hello world"))
@@ -123,22 +415,605 @@ impl EventHandler for MainState {
                             let mut j: i32 = i;
                             ui.text(im_str!("Hi from this label!"));
                             ui.text(im_str!("FPS: {:.2}", fps));
+                            ui.same_line(0.0);
+                            if ui.small_button(im_str!("MIDI devices")) {
+                                *show_devices = true;
+                            }
+                            if let Some(note) = *note_filter {
+                                ui.text(im_str!("Highlighting {}", keyboard::note_name(note)));
+                                ui.same_line(0.0);
+                                if ui.small_button(im_str!("Clear")) {
+                                    *note_filter = None;
+                                }
+                            }
+                            ui.text(im_str!("Colour tiles by"));
+                            ui.same_line(0.0);
+                            ui.radio_button(
+                                im_str!("Hand"),
+                                color_mode,
+                                tile_renderer::ColorMode::Hand,
+                            );
+                            ui.same_line(0.0);
+                            ui.radio_button(
+                                im_str!("Track"),
+                                color_mode,
+                                tile_renderer::ColorMode::Track,
+                            );
+                            ui.checkbox(im_str!("Hit effects"), &mut effects.enabled);
+                            if effects.enabled {
+                                ui.same_line(0.0);
+                                ui.checkbox(im_str!("Particles"), &mut effects.particles);
+                            }
                             ui.input_int(im_str!("delta-t"), &mut j).build();
                             if i != j {
                                 song::set_deltat(j as u64);
                             }
-                            if ui.small_button(im_str!("Reset timer")) {
-                                *rt = Instant::now();
-                            }
+                            let mut target = None;
                             if ui.small_button(im_str!("Load song")) {
-                                let res = nfd2::dialog().open().unwrap();
-                                if let nfd2::Response::Okay(path) = res {
-                                    *song = Some(song::Song::new(path));
-                                    *rt = Instant::now();
+                                if let Ok(nfd2::Response::Okay(path)) = nfd2::dialog().open() {
+                                    target = Some(path);
+                                }
+                            }
+                            if let Some(s) = song.as_ref() {
+                                ui.same_line(0.0);
+                                if ui.small_button(im_str!("Reload")) {
+                                    target = Some(s.target.clone());
+                                }
+                            }
+                            ui.radio_button(
+                                im_str!("Pair re-struck notes first in, first out"),
+                                &mut pairing.order,
+                                pairing::PairingOrder::Fifo,
+                            );
+                            ui.radio_button(
+                                im_str!("Pair re-struck notes last in, first out"),
+                                &mut pairing.order,
+                                pairing::PairingOrder::Lifo,
+                            );
+                            ui.checkbox(
+                                im_str!("Extend notes with the sustain pedal"),
+                                &mut pairing.sustain,
+                            );
+                            if let Some(path) = target {
+                                match song::Song::new(path, *pairing) {
+                                    Ok(s) => {
+                                        *song = Some(s);
+                                        *song_loaded = true;
+                                        *show_parts = true;
+                                        *load_error = None;
+                                    }
+                                    Err(e) => *load_error = Some(e.to_string()),
+                                }
+                            }
+                            if let Some(e) = load_error {
+                                ui.text_colored([1.0, 0.3, 0.3, 1.0], im_str!("{}", e));
+                            }
+                            if let Some(s) = song.as_ref() {
+                                for warning in s.warnings.iter() {
+                                    ui.text_colored([1.0, 0.8, 0.3, 1.0], im_str!("{}", warning));
                                 }
                             }
+                            if song.is_some() {
+                                ui.same_line(0.0);
+                                if ui.small_button(im_str!("Parts")) {
+                                    *show_parts = true;
+                                }
+                            }
+                            if let Some(s) = song.as_ref() {
+                                let pos = clock.position();
+                                ui.text(im_str!(
+                                    "{:.1}s / {:.1}s, measure {}",
+                                    pos.as_secs_f32(),
+                                    s.length().as_secs_f32(),
+                                    s.tempo_map.measure_at(pos) + 1
+                                ));
+                                let label = if clock.is_playing() {
+                                    im_str!("Pause")
+                                } else {
+                                    im_str!("Play")
+                                };
+                                if ui.small_button(label) {
+                                    *pause = true;
+                                }
+                                ui.same_line(0.0);
+                                if ui.small_button(im_str!("Restart")) {
+                                    score.reset(s.tiles.len());
+                                    *seek_to = Some(Duration::from_secs(0));
+                                }
+                                let mut secs = pos.as_secs_f32();
+                                if ui
+                                    .slider_float(
+                                        im_str!("Position"),
+                                        &mut secs,
+                                        0.0,
+                                        s.length().as_secs_f32(),
+                                    )
+                                    .build()
+                                {
+                                    *seek_to = Some(Duration::from_secs_f32(secs.max(0.0)));
+                                }
+                                let mut percent = *new_rate * 100.0;
+                                if ui
+                                    .slider_float(im_str!("Speed (%)"), &mut percent, 25.0, 200.0)
+                                    .build()
+                                {
+                                    *new_rate = percent / 100.0;
+                                }
+                                for (idx, (label, preset)) in [
+                                    (im_str!("50%"), 0.5),
+                                    (im_str!("75%"), 0.75),
+                                    (im_str!("100%"), 1.0),
+                                    (im_str!("120%"), 1.2),
+                                ]
+                                .iter()
+                                .enumerate()
+                                {
+                                    if idx > 0 {
+                                        ui.same_line(0.0);
+                                    }
+                                    if ui.small_button(label) {
+                                        *new_rate = *preset;
+                                    }
+                                }
+                                ui.checkbox(
+                                    im_str!("Wait for correct notes"),
+                                    &mut wait_mode.enabled,
+                                );
+                                if wait_mode.enabled {
+                                    let mut tolerance = wait_mode.tolerance as i32;
+                                    if ui
+                                        .input_int(im_str!("Wrong notes allowed"), &mut tolerance)
+                                        .build()
+                                    {
+                                        wait_mode.tolerance = tolerance.max(0) as u32;
+                                    }
+                                    if let Some(notes) = wait_mode.waiting_for(clock) {
+                                        ui.text(im_str!("Waiting for {} note(s)...", notes.len()));
+                                    }
+                                }
+                                ui.input_int(im_str!("Measure"), goto_measure).build();
+                                ui.same_line(0.0);
+                                if ui.small_button(im_str!("Go")) {
+                                    let measure = (*goto_measure).max(1) as u32 - 1;
+                                    *seek_to = Some(s.tempo_map.measure_start(measure));
+                                }
+                                ui.separator();
+                                ui.checkbox(im_str!("Loop"), &mut loop_region.enabled);
+                                ui.text(im_str!(
+                                    "A: {:.1}s  B: {:.1}s",
+                                    loop_region.start.as_secs_f32(),
+                                    loop_region.end.as_secs_f32()
+                                ));
+                                if ui.small_button(im_str!("Set A here")) {
+                                    loop_region.start = pos;
+                                }
+                                ui.same_line(0.0);
+                                if ui.small_button(im_str!("Set B here")) {
+                                    loop_region.end = pos;
+                                }
+                                ui.input_int(im_str!("From measure"), &mut loop_measures.0)
+                                    .build();
+                                ui.input_int(im_str!("To measure"), &mut loop_measures.1)
+                                    .build();
+                                if ui.small_button(im_str!("Loop measures")) {
+                                    loop_region.set_measures(
+                                        &s.tempo_map,
+                                        loop_measures.0.max(1) as u32,
+                                        loop_measures.1.max(1) as u32,
+                                    );
+                                    loop_region.enabled = true;
+                                    *seek_to = Some(loop_region.restart_point(&s.tempo_map));
+                                }
+                                let mut count_in = loop_region.count_in as i32;
+                                if ui
+                                    .input_int(im_str!("Count-in measures"), &mut count_in)
+                                    .build()
+                                {
+                                    loop_region.count_in = count_in.max(0) as u32;
+                                }
+                                let mut step = loop_region.speed_step * 100.0;
+                                if ui
+                                    .slider_float(
+                                        im_str!("Speed-up per clean pass (%)"),
+                                        &mut step,
+                                        0.0,
+                                        20.0,
+                                    )
+                                    .build()
+                                {
+                                    loop_region.speed_step = step / 100.0;
+                                }
+                                let mut max = loop_region.max_rate * 100.0;
+                                if ui
+                                    .slider_float(im_str!("Max speed (%)"), &mut max, 25.0, 200.0)
+                                    .build()
+                                {
+                                    loop_region.max_rate = max / 100.0;
+                                }
+                                if let Some(beats) = loop_region.beats_left(&s.tempo_map, pos) {
+                                    ui.text(im_str!("Count-in: {}", beats));
+                                }
+                                ui.text(im_str!(
+                                    "Passes: {} ({} clean)",
+                                    loop_region.passes,
+                                    loop_region.clean_passes
+                                ));
+                            }
+                            if let Some(engine) = engine {
+                                ui.separator();
+                                ui.checkbox(im_str!("Play song"), &mut engine.play_song);
+                                ui.checkbox(im_str!("Play my keys"), &mut engine.play_input);
+                                let mut timbre = engine.timbre;
+                                for t in synth::Timbre::ALL.iter() {
+                                    ui.radio_button(im_str!("{}", t.name()), &mut timbre, *t);
+                                }
+                                if timbre != engine.timbre {
+                                    engine.set_timbre(timbre);
+                                }
+                            }
+                            if let Some(s) = song {
+                                if ui.small_button(im_str!("Export WAV")) {
+                                    if let Ok(nfd2::Response::Okay(path)) =
+                                        nfd2::dialog_save().open()
+                                    {
+                                        let timbre = engine
+                                            .as_ref()
+                                            .map(|e| e.timbre)
+                                            .unwrap_or(synth::Timbre::Piano);
+                                        let tiles = s.sounding_tiles();
+                                        if let Err(e) = audio::export_wav(&tiles, timbre, &path) {
+                                            println!("Unable to export {:?}: {:?}", path, e);
+                                        }
+                                    }
+                                }
+                            }
+                            ui.separator();
+                            ui.text(im_str!(
+                                "Accuracy: {:.1}% ({}) at {} speed",
+                                score.accuracy(),
+                                score.grade(),
+                                score.speed_label()
+                            ));
+                            ui.text(im_str!("Combo: {} (max {})", score.combo, score.max_combo));
+                            ui.text(im_str!(
+                                "Perfect {} / Great {} / Good {} / Miss {}",
+                                score.perfect,
+                                score.great,
+                                score.good,
+                                score.miss
+                            ));
+                            ui.text(im_str!("Wrong notes: {}", score.wrong_notes));
+                            ui.text(im_str!("Mean offset: {:+.1} ms", score.mean_offset()));
+                            if let Some(accuracy) = score.dynamics_accuracy() {
+                                ui.text(im_str!("Dynamics accuracy: {:.1}%", accuracy));
+                            }
+                            if let Some(accuracy) = score.pedal_accuracy() {
+                                ui.text(im_str!("Pedal accuracy: {:.1}%", accuracy));
+                            }
+                            if let Some((
+                                judge::Outcome::Hit {
+                                    velocity: (played, written),
+                                    ..
+                                },
+                                _,
+                            )) = score.last
+                            {
+                                if score.dynamics.enabled {
+                                    ui.text(im_str!(
+                                        "Last note: played {} ({}), written {} ({})",
+                                        judge::Dynamic::from_velocity(played).marking(),
+                                        played,
+                                        judge::Dynamic::from_velocity(written).marking(),
+                                        written
+                                    ));
+                                }
+                            }
+                            let dynamics = &mut score.dynamics;
+                            ui.checkbox(im_str!("Judge dynamics"), &mut dynamics.enabled);
+                            if dynamics.enabled {
+                                ui.radio_button(
+                                    im_str!("Linear"),
+                                    &mut dynamics.curve,
+                                    judge::ToleranceCurve::Linear,
+                                );
+                                ui.same_line(0.0);
+                                ui.radio_button(
+                                    im_str!("Gaussian"),
+                                    &mut dynamics.curve,
+                                    judge::ToleranceCurve::Gaussian,
+                                );
+                                ui.same_line(0.0);
+                                ui.radio_button(
+                                    im_str!("pp-ff buckets"),
+                                    &mut dynamics.curve,
+                                    judge::ToleranceCurve::Bucket,
+                                );
+                                let mut tolerance = dynamics.tolerance as i32;
+                                if ui
+                                    .input_int(im_str!("Velocity tolerance"), &mut tolerance)
+                                    .build()
+                                {
+                                    dynamics.tolerance = tolerance.max(1).min(127) as u8;
+                                }
+                            }
+                            ui.checkbox(im_str!("Judge pedal timing"), &mut score.judge_pedal);
+                            let windows = &mut score.windows;
+                            input_millis(ui, im_str!("Perfect (ms)"), &mut windows.perfect);
+                            input_millis(ui, im_str!("Great (ms)"), &mut windows.great);
+                            input_millis(ui, im_str!("Good (ms)"), &mut windows.good);
                         });
+                    if let Some(s) = song.as_mut().filter(|_| *show_parts) {
+                        imgui::Window::new(im_str!("Parts"))
+                            .size([450.0, 300.0], imgui::Condition::FirstUseEver)
+                            .position([400.0, 50.0], imgui::Condition::FirstUseEver)
+                            .opened(show_parts)
+                            .build(ui, || {
+                                for (idx, part) in s.parts.iter_mut().enumerate() {
+                                    ui.text(im_str!(
+                                        "{}: {} ({} notes)",
+                                        part.name,
+                                        part.instrument(),
+                                        part.note_count
+                                    ));
+                                    let mut role = part.role;
+                                    ui.radio_button(
+                                        im_str!("Play##{}", idx),
+                                        &mut role,
+                                        song::PartRole::Play,
+                                    );
+                                    ui.same_line(0.0);
+                                    ui.radio_button(
+                                        im_str!("Accompany##{}", idx),
+                                        &mut role,
+                                        song::PartRole::Accompany,
+                                    );
+                                    ui.same_line(0.0);
+                                    ui.radio_button(
+                                        im_str!("Mute##{}", idx),
+                                        &mut role,
+                                        song::PartRole::Mute,
+                                    );
+                                    if role != part.role {
+                                        part.role = role;
+                                        *roles_changed = true;
+                                    }
+                                }
+                                ui.separator();
+                                ui.text(im_str!("Hands"));
+                                let mut split = s.hand_split;
+                                ui.radio_button(
+                                    im_str!("From parts"),
+                                    &mut split,
+                                    hands::HandSplit::ByPart,
+                                );
+                                ui.same_line(0.0);
+                                ui.radio_button(
+                                    im_str!("Automatic split"),
+                                    &mut split,
+                                    hands::HandSplit::Automatic,
+                                );
+                                let mut mode = s.hand_mode;
+                                ui.radio_button(
+                                    im_str!("Both hands"),
+                                    &mut mode,
+                                    hands::HandMode::Both,
+                                );
+                                ui.same_line(0.0);
+                                ui.radio_button(
+                                    im_str!("Left hand"),
+                                    &mut mode,
+                                    hands::HandMode::Left,
+                                );
+                                ui.same_line(0.0);
+                                ui.radio_button(
+                                    im_str!("Right hand"),
+                                    &mut mode,
+                                    hands::HandMode::Right,
+                                );
+                                if split != s.hand_split || mode != s.hand_mode {
+                                    s.hand_split = split;
+                                    s.hand_mode = mode;
+                                    *roles_changed = true;
+                                }
+                                ui.separator();
+                                ui.text(im_str!(
+                                    "Fit to keyboard ({} to {})",
+                                    keyboard::note_name(fit_range.low),
+                                    keyboard::note_name(fit_range.high)
+                                ));
+                                let mut semitones = transform.transpose as i32;
+                                if ui.input_int(im_str!("Transpose"), &mut semitones).build() {
+                                    transform.transpose = semitones.max(-48).min(48) as i8;
+                                }
+                                ui.radio_button(
+                                    im_str!("Keep"),
+                                    &mut transform.fit,
+                                    transform::FitMode::Keep,
+                                );
+                                ui.same_line(0.0);
+                                ui.radio_button(
+                                    im_str!("Fold into range"),
+                                    &mut transform.fit,
+                                    transform::FitMode::Fold,
+                                );
+                                ui.same_line(0.0);
+                                ui.radio_button(
+                                    im_str!("Drop"),
+                                    &mut transform.fit,
+                                    transform::FitMode::Drop,
+                                );
+                                transform.range = fit_range;
+                                let preview = transform.preview(&s.source_tiles());
+                                ui.text(im_str!(
+                                    "{} of {} notes out of range, {} folded, {} dropped",
+                                    preview.out_of_range,
+                                    preview.total,
+                                    preview.folded,
+                                    preview.dropped
+                                ));
+                                if let Some((low, high)) = preview.span {
+                                    ui.text(im_str!(
+                                        "Notes from {} to {}",
+                                        keyboard::note_name(low),
+                                        keyboard::note_name(high)
+                                    ));
+                                }
+                                if *transform != s.transform {
+                                    if ui.small_button(im_str!("Apply")) {
+                                        s.transform = *transform;
+                                        *roles_changed = true;
+                                    }
+                                    ui.same_line(0.0);
+                                    if ui.small_button(im_str!("Revert")) {
+                                        *transform = s.transform;
+                                    }
+                                } else if s.transform.transpose != 0
+                                    || s.transform.fit != transform::FitMode::Keep
+                                {
+                                    ui.text(im_str!("Applied"));
+                                }
+                            });
+                        if *roles_changed {
+                            s.apply_roles();
+                        }
+                    }
+                    if *show_devices {
+                        imgui::Window::new(im_str!("MIDI devices"))
+                            .size([350.0, 200.0], imgui::Condition::FirstUseEver)
+                            .position([400.0, 400.0], imgui::Condition::FirstUseEver)
+                            .opened(show_devices)
+                            .build(ui, || {
+                                if devices.available.is_empty() {
+                                    ui.text(im_str!("No MIDI input devices found."));
+                                }
+                                let mut changed: Option<(String, bool)> = None;
+                                for (idx, name) in devices.available.iter().enumerate() {
+                                    let mut selected = devices.is_selected(name);
+                                    if ui.checkbox(im_str!("{}##{}", name, idx), &mut selected) {
+                                        changed = Some((name.clone(), selected));
+                                    }
+                                    if selected && !devices.is_connected(name) {
+                                        ui.same_line(0.0);
+                                        ui.text_colored(
+                                            [1.0, 0.3, 0.3, 1.0],
+                                            im_str!("not connected"),
+                                        );
+                                    }
+                                }
+                                if let Some((name, selected)) = changed {
+                                    devices.set_selected(&name, selected);
+                                }
+                                if ui.small_button(im_str!("Rescan")) {
+                                    devices.rescan();
+                                    midi_out.refresh();
+                                }
+                                ui.separator();
+                                ui.text(im_str!(
+                                    "Output: {}",
+                                    midi_out.port_name.as_deref().unwrap_or("none")
+                                ));
+                                let mut connect_to: Option<String> = None;
+                                for (idx, name) in midi_out.available.iter().enumerate() {
+                                    if ui.small_button(im_str!("{}##out{}", name, idx)) {
+                                        connect_to = Some(name.clone());
+                                    }
+                                }
+                                if let Some(name) = connect_to {
+                                    midi_out.connect(&name);
+                                }
+                                #[cfg(unix)]
+                                {
+                                    if ui.small_button(im_str!("Virtual port")) {
+                                        midi_out.connect_virtual();
+                                    }
+                                }
+                                if midi_out.is_connected() {
+                                    ui.same_line(0.0);
+                                    if ui.small_button(im_str!("Disconnect")) {
+                                        midi_out.disconnect();
+                                    }
+                                }
+                                ui.checkbox(
+                                    im_str!("Send accompaniment"),
+                                    &mut midi_out.send_accompaniment,
+                                );
+                                ui.checkbox(
+                                    im_str!("Send my part as a guide"),
+                                    &mut midi_out.send_guide,
+                                );
+                                ui.separator();
+                                ui.text(im_str!("Keyboard size"));
+                                let mut count = key_count.unwrap_or(0);
+                                ui.radio_button(im_str!("Fit to song"), &mut count, 0);
+                                for &(keys, _) in KeyRange::PRESETS.iter() {
+                                    ui.same_line(0.0);
+                                    ui.radio_button(im_str!("{}", keys), &mut count, keys);
+                                }
+                                if count != key_count.unwrap_or(0) {
+                                    *key_count = Some(count).filter(|&i| i > 0);
+                                    *new_range = true;
+                                }
+                                ui.separator();
+                                if ui.checkbox(
+                                    im_str!("Computer keyboard as piano"),
+                                    &mut qwerty.enabled,
+                                ) && !qwerty.enabled
+                                {
+                                    qwerty.release_all();
+                                }
+                                if qwerty.enabled {
+                                    ui.text(im_str!(
+                                        "Bottom row starts at C{} (- and = to change)",
+                                        qwerty.octave
+                                    ));
+                                    let mut velocity = qwerty.velocity as i32;
+                                    if ui.input_int(im_str!("Velocity"), &mut velocity).build() {
+                                        qwerty.velocity = velocity.max(1).min(127) as u8;
+                                    }
+                                    if ui.input_int(im_str!("Semitone"), remap_note).build() {
+                                        *remap_note = (*remap_note).max(0).min(48);
+                                    }
+                                    ui.same_line(0.0);
+                                    if ui.small_button(im_str!("Assign key")) {
+                                        qwerty.learning = Some(*remap_note as u8);
+                                    }
+                                    if let Some(offset) = qwerty.learning {
+                                        ui.text(im_str!("Press a key to play semitone {}", offset));
+                                    }
+                                    if ui.small_button(im_str!("Default keys")) {
+                                        qwerty.map = qwerty::DEFAULT_MAP.to_vec();
+                                    }
+                                }
+                            });
+                    }
                 });
+            if loaded {
+                self.transform = transform::Transform::default();
+                self.reload_tiles(Duration::from_secs(0));
+            } else if parts_changed {
+                self.reload_tiles(self.clock.position());
+            }
+            if toggle_pause {
+                self.toggle_pause();
+            }
+            if range_changed {
+                // Songs fitted to the old keyboard need fitting to the new one.
+                let range = self.preset_range().unwrap_or(KeyRange::FULL);
+                if let Some(song) = &mut self.current_song {
+                    if song.transform.range != range {
+                        song.transform.range = range;
+                        song.apply_roles();
+                    }
+                }
+                self.reload_tiles(self.clock.position());
+            }
+            if let Some(pos) = seek {
+                self.seek(pos);
+            }
+            if (rate - self.clock.rate()).abs() > f32::EPSILON {
+                self.clock.set_rate(rate);
+                self.scoreboard.set_rate(rate);
+            }
         }
 
         graphics::draw(
@@ -154,30 +1029,46 @@ impl EventHandler for MainState {
 
     fn mouse_motion_event(&mut self, _ctx: &mut Context, x: f32, y: f32, _dx: f32, _dy: f32) {
         self.imgui_wrapper.update_mouse_pos(x, y);
+        // Dragging across the piano slides from key to key.
+        if self.mouse_down {
+            self.press_mouse_key(x, y);
+        }
     }
 
-    fn mouse_button_down_event(
-        &mut self,
-        _ctx: &mut Context,
-        button: MouseButton,
-        _x: f32,
-        _y: f32,
-    ) {
+    // Touchscreens send their touches as mouse events as well.
+    fn mouse_button_down_event(&mut self, _ctx: &mut Context, button: MouseButton, x: f32, y: f32) {
         self.imgui_wrapper.update_mouse_down((
             button == MouseButton::Left,
             button == MouseButton::Right,
             button == MouseButton::Middle,
         ));
+        if self.imgui_wrapper.wants_mouse() {
+            return;
+        }
+        match button {
+            MouseButton::Left => {
+                self.mouse_down = true;
+                self.press_mouse_key(x, y);
+            }
+            MouseButton::Right => {
+                if let Some(key) = self.board.key_at(x, y) {
+                    self.note_filter = if self.note_filter == Some(key) {
+                        None
+                    } else {
+                        Some(key)
+                    };
+                }
+            }
+            _ => (),
+        }
     }
 
-    fn mouse_button_up_event(
-        &mut self,
-        _ctx: &mut Context,
-        _button: MouseButton,
-        _x: f32,
-        _y: f32,
-    ) {
+    fn mouse_button_up_event(&mut self, _ctx: &mut Context, button: MouseButton, _x: f32, _y: f32) {
         self.imgui_wrapper.update_mouse_down((false, false, false));
+        if button == MouseButton::Left {
+            self.mouse_down = false;
+            self.release_mouse_key();
+        }
     }
 
     fn key_down_event(
@@ -185,15 +1076,19 @@ impl EventHandler for MainState {
         _ctx: &mut Context,
         keycode: KeyCode,
         keymods: KeyMods,
-        _repeat: bool,
+        repeat: bool,
     ) {
         if keycode == KeyCode::Escape {
             self.show_ui = !self.show_ui;
         }
+        if !self.imgui_wrapper.wants_keyboard() && self.qwerty.key_down(keycode, repeat) {
+            return;
+        }
         self.imgui_wrapper.update_key_down(keycode, keymods);
     }
 
     fn key_up_event(&mut self, _ctx: &mut Context, keycode: KeyCode, keymods: KeyMods) {
+        self.qwerty.key_up(keycode);
         self.imgui_wrapper.update_key_up(keycode, keymods);
     }
 
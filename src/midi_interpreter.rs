@@ -1,4 +1,4 @@
-use midly::{Event, EventKind, MetaMessage};
+use midly::Event;
 use std::vec::Vec;
 
 // Algorithms and concepts taken from  'https://github.com/mido/mido/', covered by the MIT license
@@ -17,52 +17,283 @@ pub fn to_abstime(v: Vec<Event<'_>>) -> Vec<Event<'_>> {
         .collect()
 }
 
-/// Convert all events' delay items from absolute to relative timings.
-pub fn to_reltime(v: Vec<Event<'_>>) -> Vec<Event<'_>> {
-    let mut now: u32 = 0;
-    v.iter()
-        .map(move |i: &Event<'_>| {
-            let delta: u32 = i.delta.into();
-            let mut n = i.clone();
-            n.delta = (delta - now).into();
-            now = delta;
-            n
-        })
-        .collect()
+/// General MIDI instrument names, indexed by program number.
+static GM_INSTRUMENTS: [&str; 128] = [
+    "Acoustic Grand Piano",
+    "Bright Acoustic Piano",
+    "Electric Grand Piano",
+    "Honky-tonk Piano",
+    "Electric Piano 1",
+    "Electric Piano 2",
+    "Harpsichord",
+    "Clavinet",
+    "Celesta",
+    "Glockenspiel",
+    "Music Box",
+    "Vibraphone",
+    "Marimba",
+    "Xylophone",
+    "Tubular Bells",
+    "Dulcimer",
+    "Drawbar Organ",
+    "Percussive Organ",
+    "Rock Organ",
+    "Church Organ",
+    "Reed Organ",
+    "Accordion",
+    "Harmonica",
+    "Tango Accordion",
+    "Acoustic Guitar (nylon)",
+    "Acoustic Guitar (steel)",
+    "Electric Guitar (jazz)",
+    "Electric Guitar (clean)",
+    "Electric Guitar (muted)",
+    "Overdriven Guitar",
+    "Distortion Guitar",
+    "Guitar Harmonics",
+    "Acoustic Bass",
+    "Electric Bass (finger)",
+    "Electric Bass (pick)",
+    "Fretless Bass",
+    "Slap Bass 1",
+    "Slap Bass 2",
+    "Synth Bass 1",
+    "Synth Bass 2",
+    "Violin",
+    "Viola",
+    "Cello",
+    "Contrabass",
+    "Tremolo Strings",
+    "Pizzicato Strings",
+    "Orchestral Harp",
+    "Timpani",
+    "String Ensemble 1",
+    "String Ensemble 2",
+    "Synth Strings 1",
+    "Synth Strings 2",
+    "Choir Aahs",
+    "Voice Oohs",
+    "Synth Choir",
+    "Orchestra Hit",
+    "Trumpet",
+    "Trombone",
+    "Tuba",
+    "Muted Trumpet",
+    "French Horn",
+    "Brass Section",
+    "Synth Brass 1",
+    "Synth Brass 2",
+    "Soprano Sax",
+    "Alto Sax",
+    "Tenor Sax",
+    "Baritone Sax",
+    "Oboe",
+    "English Horn",
+    "Bassoon",
+    "Clarinet",
+    "Piccolo",
+    "Flute",
+    "Recorder",
+    "Pan Flute",
+    "Blown Bottle",
+    "Shakuhachi",
+    "Whistle",
+    "Ocarina",
+    "Lead 1 (square)",
+    "Lead 2 (sawtooth)",
+    "Lead 3 (calliope)",
+    "Lead 4 (chiff)",
+    "Lead 5 (charang)",
+    "Lead 6 (voice)",
+    "Lead 7 (fifths)",
+    "Lead 8 (bass + lead)",
+    "Pad 1 (new age)",
+    "Pad 2 (warm)",
+    "Pad 3 (polysynth)",
+    "Pad 4 (choir)",
+    "Pad 5 (bowed)",
+    "Pad 6 (metallic)",
+    "Pad 7 (halo)",
+    "Pad 8 (sweep)",
+    "FX 1 (rain)",
+    "FX 2 (soundtrack)",
+    "FX 3 (crystal)",
+    "FX 4 (atmosphere)",
+    "FX 5 (brightness)",
+    "FX 6 (goblins)",
+    "FX 7 (echoes)",
+    "FX 8 (sci-fi)",
+    "Sitar",
+    "Banjo",
+    "Shamisen",
+    "Koto",
+    "Kalimba",
+    "Bagpipe",
+    "Fiddle",
+    "Shanai",
+    "Tinkle Bell",
+    "Agogo",
+    "Steel Drums",
+    "Woodblock",
+    "Taiko Drum",
+    "Melodic Tom",
+    "Synth Drum",
+    "Reverse Cymbal",
+    "Guitar Fret Noise",
+    "Breath Noise",
+    "Seashore",
+    "Bird Tweet",
+    "Telephone Ring",
+    "Helicopter",
+    "Applause",
+    "Gunshot",
+];
+
+/// The General MIDI channel reserved for percussion (channel 10, counting from one).
+pub const DRUM_CHANNEL: u8 = 9;
+
+/// Name of the instrument `program` selects on `channel`.
+pub fn instrument_name(channel: u8, program: u8) -> &'static str {
+    if channel == DRUM_CHANNEL {
+        "Drums"
+    } else {
+        GM_INSTRUMENTS[(program & 0x7F) as usize]
+    }
 }
 
-/// Remove all extra `EndOfTrack` messages.
-pub fn fix_track_end(v: Vec<Event<'_>>) -> Vec<Event<'_>> {
-    let mut out: Vec<Event<'_>> = vec![];
-    let mut accum: u32 = 0;
-    for msg in v {
-        if msg.kind == EventKind::Meta(MetaMessage::EndOfTrack) {
-            let delta: u32 = msg.delta.into();
-            accum += delta;
-        } else {
-            let mut ev = msg.clone();
-            if accum > 0 {
-                let delta: u32 = msg.delta.into();
-                ev.delta = (delta + accum).into();
-            }
-            out.push(ev)
+fn read_u32(raw: &[u8]) -> u32 {
+    u32::from_be_bytes([raw[0], raw[1], raw[2], raw[3]])
+}
+
+fn set_track_count(file: &mut Vec<u8>, count: u16) {
+    file[10..12].copy_from_slice(&count.to_be_bytes());
+}
+
+/// Attempt to salvage a file whose last track chunk was cut short.
+/// Returns candidate repairs to try in order: first with the truncated track
+/// closed off where the data ends, then with that track dropped entirely.
+pub fn repair_truncated(raw: &[u8]) -> Vec<Vec<u8>> {
+    if raw.len() < 14 || &raw[0..4] != b"MThd" {
+        return vec![];
+    }
+    let mut pos = 8 + read_u32(&raw[4..8]) as usize;
+    if pos > raw.len() || pos < 14 {
+        return vec![];
+    }
+    let mut complete = raw[..pos].to_vec();
+    let mut tracks: u16 = 0;
+    while pos + 8 <= raw.len() {
+        let end = pos + 8 + read_u32(&raw[pos + 4..pos + 8]) as usize;
+        if end > raw.len() {
+            let data = &raw[pos + 8..];
+            let mut closed = complete.clone();
+            closed.extend_from_slice(b"MTrk");
+            closed.extend_from_slice(&(data.len() as u32 + 4).to_be_bytes());
+            closed.extend_from_slice(data);
+            closed.extend_from_slice(&[0x00, 0xFF, 0x2F, 0x00]);
+            set_track_count(&mut closed, tracks + 1);
+            set_track_count(&mut complete, tracks);
+            return vec![closed, complete];
+        }
+        if &raw[pos..pos + 4] == b"MTrk" {
+            tracks += 1;
         }
+        complete.extend_from_slice(&raw[pos..end]);
+        pos = end;
     }
-    out.push(Event {
-        delta: accum.into(),
-        kind: EventKind::Meta(MetaMessage::EndOfTrack),
-    });
-    out
+    // No chunk was cut short, but the header may promise more tracks than there are.
+    set_track_count(&mut complete, tracks);
+    vec![complete]
 }
 
-/// Convert an array of tracks to one array containing all the events.
-pub fn as_merged(v: Vec<Vec<Event<'_>>>) -> Vec<Event<'_>> {
-    let mut messages: Vec<Event<'_>> = vec![];
-    v.iter()
-        .for_each(|i| messages.extend(to_abstime(i.clone())));
-    messages.sort_by_key(|i: &Event<'_>| {
-        let n: u32 = i.delta.into();
-        n
-    });
-    fix_track_end(to_reltime(messages))
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use midly::Smf;
+
+    const END_OF_TRACK: [u8; 4] = [0x00, 0xFF, 0x2F, 0x00];
+
+    /// A format 1 header with 480 ticks per beat.
+    fn header(tracks: u16) -> Vec<u8> {
+        let mut out = b"MThd\0\0\0\x06\0\x01".to_vec();
+        out.extend_from_slice(&tracks.to_be_bytes());
+        out.extend_from_slice(&480u16.to_be_bytes());
+        out
+    }
+
+    fn chunk(data: &[u8]) -> Vec<u8> {
+        let mut out = b"MTrk".to_vec();
+        out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        out.extend_from_slice(data);
+        out
+    }
+
+    /// Sets the tempo to 120 bpm.
+    fn conductor() -> Vec<u8> {
+        chunk(&[
+            0x00, 0xFF, 0x51, 0x03, 0x07, 0xA1, 0x20, 0x00, 0xFF, 0x2F, 0x00,
+        ])
+    }
+
+    /// Middle C for one beat.
+    fn melody() -> Vec<u8> {
+        let mut data = vec![0x00, 0x90, 0x3C, 0x40, 0x83, 0x60, 0x80, 0x3C, 0x40];
+        data.extend_from_slice(&END_OF_TRACK);
+        chunk(&data)
+    }
+
+    fn file(tracks: u16, chunks: &[Vec<u8>]) -> Vec<u8> {
+        let mut out = header(tracks);
+        for chunk in chunks {
+            out.extend_from_slice(chunk);
+        }
+        out
+    }
+
+    #[test]
+    fn valid_files_are_left_alone() {
+        let valid = file(2, &[conductor(), melody()]);
+        assert_eq!(repair_truncated(&valid), vec![valid.clone()]);
+    }
+
+    #[test]
+    fn headers_promising_missing_tracks_are_corrected() {
+        let raw = file(3, &[conductor(), melody()]);
+        assert_eq!(
+            repair_truncated(&raw),
+            vec![file(2, &[conductor(), melody()])]
+        );
+    }
+
+    #[test]
+    fn chunks_longer_than_the_file_are_closed_off() {
+        let valid = file(2, &[conductor(), melody()]);
+        // Everything but the end of track, which the chunk's length still counts.
+        let raw = &valid[..valid.len() - END_OF_TRACK.len()];
+        let repairs = repair_truncated(raw);
+        assert_eq!(repairs, vec![valid, file(1, &[conductor()])]);
+        assert_eq!(Smf::parse(&repairs[0]).unwrap().tracks.len(), 2);
+    }
+
+    #[test]
+    fn chunks_cut_off_mid_event_can_be_dropped() {
+        let mut raw = file(2, &[conductor()]);
+        let melody = melody();
+        // The chunk header and half a note-on.
+        raw.extend_from_slice(&melody[..8 + 3]);
+        let repairs = repair_truncated(&raw);
+        assert_eq!(repairs.len(), 2);
+
+        let mut closed = file(2, &[conductor()]);
+        closed.extend_from_slice(&chunk(&[0x00, 0x90, 0x3C, 0x00, 0xFF, 0x2F, 0x00]));
+        assert_eq!(repairs[0], closed);
+        assert_eq!(repairs[1], file(1, &[conductor()]));
+        assert_eq!(Smf::parse(&repairs[1]).unwrap().tracks.len(), 1);
+    }
+
+    #[test]
+    fn other_files_cannot_be_repaired() {
+        assert!(repair_truncated(b"RIFF\0\0\0\x04WAVE").is_empty());
+        assert!(repair_truncated(&header(1)[..10]).is_empty());
+    }
 }
@@ -0,0 +1,286 @@
+use midir::{MidiOutput, MidiOutputConnection};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+use std::vec::Vec;
+
+use crate::audio::NoteSink;
+use crate::clock::SongClock;
+use crate::pairing::SUSTAIN_CONTROLLER;
+use crate::song::Tile;
+
+const ALL_NOTES_OFF: u8 = 123;
+
+/// How far ahead of the song clock notes are handed to the writer thread.
+/// This needs to be more than a frame, so nothing is due before it's been handed over.
+pub const LOOKAHEAD: Duration = Duration::from_millis(50);
+
+enum Command {
+    /// Send a message once the given moment comes.
+    Send(Instant, Vec<u8>),
+    /// Forget every message still waiting to be sent.
+    Clear,
+}
+
+/// Sends each message when it's due, until the `MidiOut` hangs up.
+fn write(mut conn: MidiOutputConnection, commands: Receiver<Command>) {
+    // Sorted by when each message is due.
+    let mut queue: Vec<(Instant, Vec<u8>)> = vec![];
+    loop {
+        while queue
+            .first()
+            .map(|i| i.0 <= Instant::now())
+            .unwrap_or(false)
+        {
+            let (_, message) = queue.remove(0);
+            if let Err(e) = conn.send(&message) {
+                println!("Unable to send MIDI: {:?}", e);
+            }
+        }
+        let command = match queue.first() {
+            Some(&(at, _)) => {
+                match commands.recv_timeout(at.saturating_duration_since(Instant::now())) {
+                    Ok(command) => command,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+            }
+            None => match commands.recv() {
+                Ok(command) => command,
+                Err(_) => return,
+            },
+        };
+        match command {
+            // After anything due at the same time, so messages keep their order.
+            Command::Send(at, message) => {
+                let idx = queue.iter().take_while(|i| i.0 <= at).count();
+                queue.insert(idx, (at, message));
+            }
+            Command::Clear => queue.clear(),
+        }
+    }
+}
+
+/// When song time `time` comes, given that the song was at `pos` at `now` and is
+/// playing at `rate`. Times already passed are due straight away.
+fn due_at(
+    time: Duration,
+    (now, pos, rate): (Instant, Duration, f32),
+    clock_running: bool,
+) -> Instant {
+    match time.checked_sub(pos) {
+        Some(ahead) if clock_running => now + ahead.div_f32(rate),
+        _ => now,
+    }
+}
+
+/// Sends the song to an external synth, such as a digital piano's own sound engine.
+/// The sequencer runs `LOOKAHEAD` ahead of the song, and a writer thread sends each
+/// note when the song clock reaches it, so the timing doesn't depend on the frame rate.
+pub struct MidiOut {
+    writer: Option<Sender<Command>>,
+    /// The moment the song clock was last read, with its position and rate then.
+    anchor: (Instant, Duration, f32),
+    playing: bool,
+    /// The port currently connected to.
+    pub port_name: Option<String>,
+    /// Names of every output port seen on the last refresh.
+    pub available: Vec<String>,
+    pub send_accompaniment: bool,
+    /// Also send the part being learned, as a guide.
+    pub send_guide: bool,
+    /// The last program sent on each channel.
+    programs: [Option<u8>; 16],
+}
+
+impl MidiOut {
+    pub fn new() -> Self {
+        let mut out = Self {
+            writer: None,
+            anchor: (Instant::now(), Duration::from_secs(0), 1.0),
+            playing: false,
+            port_name: None,
+            available: vec![],
+            send_accompaniment: true,
+            send_guide: false,
+            programs: [None; 16],
+        };
+        out.refresh();
+        out
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.writer.is_some()
+    }
+
+    /// Line the song up with real time, so the notes sent next are due at the right moment.
+    pub fn sync(&mut self, clock: &SongClock) {
+        let now = Instant::now();
+        self.anchor = (now, clock.position_at(now), clock.rate());
+        self.playing = clock.is_playing();
+    }
+
+    /// The song time to play up to, `LOOKAHEAD` ahead of `clock`.
+    pub fn horizon(clock: &SongClock) -> Duration {
+        clock.position_at(Instant::now() + LOOKAHEAD)
+    }
+
+    fn start_writer(&mut self, conn: MidiOutputConnection) {
+        let (sender, receiver) = channel();
+        thread::spawn(move || write(conn, receiver));
+        self.writer = Some(sender);
+    }
+
+    /// List the output ports again.
+    pub fn refresh(&mut self) {
+        self.available = match MidiOutput::new("synthy scanner") {
+            Ok(midi_out) => midi_out
+                .ports()
+                .iter()
+                .filter_map(|p| midi_out.port_name(p).ok())
+                .collect(),
+            Err(e) => {
+                println!("Unable to list MIDI outputs: {:?}", e);
+                vec![]
+            }
+        };
+    }
+
+    /// Connect to the output port called `name`, dropping any previous connection.
+    pub fn connect(&mut self, name: &str) {
+        self.disconnect();
+        let midi_out = match MidiOutput::new("synthy writer") {
+            Ok(midi_out) => midi_out,
+            Err(e) => {
+                println!("Unable to open MIDI output: {:?}", e);
+                return;
+            }
+        };
+        let port = midi_out
+            .ports()
+            .into_iter()
+            .find(|p| midi_out.port_name(p).map(|n| n == name).unwrap_or(false));
+        let port = match port {
+            Some(port) => port,
+            None => {
+                println!("MIDI output {:?} has gone away", name);
+                return;
+            }
+        };
+        match midi_out.connect(&port, "synthy-write-output") {
+            Ok(conn) => {
+                println!("Sending to {:?}", name);
+                self.start_writer(conn);
+                self.port_name = Some(name.to_string());
+            }
+            Err(e) => println!("Unable to connect to {:?}: {:?}", name, e),
+        }
+    }
+
+    /// Open a virtual output port that other programs can connect to.
+    #[cfg(unix)]
+    pub fn connect_virtual(&mut self) {
+        use midir::os::unix::VirtualOutput;
+
+        self.disconnect();
+        let midi_out = match MidiOutput::new("synthy") {
+            Ok(midi_out) => midi_out,
+            Err(e) => {
+                println!("Unable to open MIDI output: {:?}", e);
+                return;
+            }
+        };
+        match midi_out.create_virtual("synthy output") {
+            Ok(conn) => {
+                self.start_writer(conn);
+                self.port_name = Some("synthy output (virtual)".to_string());
+            }
+            Err(e) => println!("Unable to create a virtual MIDI port: {:?}", e),
+        }
+    }
+
+    pub fn disconnect(&mut self) {
+        self.all_notes_off();
+        // The writer sends what it's been given, then stops when it finds no one's left.
+        self.writer = None;
+        self.port_name = None;
+        self.programs = [None; 16];
+    }
+
+    /// Send `message` when the song reaches `time`.
+    fn send_at(&mut self, time: Duration, message: &[u8]) {
+        let at = due_at(time, self.anchor, self.playing);
+        self.schedule(at, message);
+    }
+
+    fn send(&mut self, message: &[u8]) {
+        self.schedule(Instant::now(), message);
+    }
+
+    fn schedule(&mut self, at: Instant, message: &[u8]) {
+        if let Some(writer) = &self.writer {
+            let _ = writer.send(Command::Send(at, message.to_vec()));
+        }
+    }
+}
+
+impl NoteSink for MidiOut {
+    fn plays_song(&self) -> bool {
+        self.send_guide
+    }
+
+    fn plays_accompaniment(&self) -> bool {
+        self.send_accompaniment
+    }
+
+    fn note_on(&mut self, tile: &Tile) {
+        let channel = tile.channel & 0x0F;
+        if self.programs[channel as usize] != Some(tile.program) {
+            self.programs[channel as usize] = Some(tile.program);
+            self.send_at(tile.start, &[0xC0 | channel, tile.program & 0x7F]);
+        }
+        self.send_at(
+            tile.start,
+            &[0x90 | channel, tile.note, tile.velocity.max(1) & 0x7F],
+        );
+    }
+
+    fn note_off(&mut self, channel: u8, key: u8, at: Duration) {
+        self.send_at(at, &[0x80 | (channel & 0x0F), key, 0]);
+    }
+
+    fn all_notes_off(&mut self) {
+        // Anything still to come belongs to where the song was before.
+        if let Some(writer) = &self.writer {
+            let _ = writer.send(Command::Clear);
+        }
+        for channel in 0..16u8 {
+            self.send(&[0xB0 | channel, SUSTAIN_CONTROLLER, 0]);
+            self.send(&[0xB0 | channel, ALL_NOTES_OFF, 0]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    #[test]
+    fn notes_are_due_when_the_song_reaches_them() {
+        let now = Instant::now();
+        assert_eq!(due_at(ms(1500), (now, ms(1000), 1.0), true), now + ms(500));
+        // At half speed the song takes twice as long to get there.
+        assert_eq!(due_at(ms(1500), (now, ms(1000), 0.5), true), now + ms(1000));
+    }
+
+    #[test]
+    fn late_notes_and_stopped_clocks_send_straight_away() {
+        let now = Instant::now();
+        assert_eq!(due_at(ms(900), (now, ms(1000), 1.0), true), now);
+        assert_eq!(due_at(ms(1500), (now, ms(1000), 1.0), false), now);
+    }
+}
@@ -0,0 +1,299 @@
+use midly::{Event, EventKind, MidiMessage};
+use std::collections::{HashMap, VecDeque};
+use std::vec::Vec;
+
+/// Which note-on a note-off ends when the same key is struck again before being released.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PairingOrder {
+    /// The earliest one still sounding.
+    Fifo,
+    /// The most recent one.
+    Lifo,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct PairingOptions {
+    pub order: PairingOrder,
+    /// Keep notes sounding while the sustain pedal (CC64) is held.
+    pub sustain: bool,
+}
+
+impl Default for PairingOptions {
+    fn default() -> Self {
+        Self {
+            order: PairingOrder::Fifo,
+            sustain: false,
+        }
+    }
+}
+
+/// A complete note, with its times in absolute ticks.
+#[derive(Debug, Copy, Clone)]
+pub struct RawNote {
+    pub channel: u8,
+    pub key: u8,
+    pub vel: u8,
+    pub program: u8,
+    pub start: u32,
+    pub end: u32,
+}
+
+pub const SUSTAIN_CONTROLLER: u8 = 64;
+
+/// A pending note-on: start tick, velocity and program.
+type Open = (u32, u8, u8);
+
+/// Pair up the note-ons and note-offs of one track, whose events must have absolute times.
+/// Notes still sounding when the track ends are ended there.
+pub fn pair_notes(events: &[Event<'_>], opts: PairingOptions) -> Vec<RawNote> {
+    let mut notes: Vec<RawNote> = vec![];
+    let mut open: HashMap<(u8, u8), VecDeque<Open>> = HashMap::new();
+    // Notes released while the pedal was down, still sounding until it comes up.
+    let mut held: HashMap<(u8, u8), Vec<Open>> = HashMap::new();
+    let mut pedal = [false; 16];
+    let mut programs = [0u8; 16];
+    let mut now: u32 = 0;
+
+    let finish = |notes: &mut Vec<RawNote>, (channel, key): (u8, u8), note: Open, end: u32| {
+        let (start, vel, program) = note;
+        notes.push(RawNote {
+            channel,
+            key,
+            vel,
+            program,
+            start,
+            end,
+        });
+    };
+
+    for ev in events {
+        now = ev.delta.into();
+        let (channel, message) = match ev.kind {
+            EventKind::Midi { channel, message } => (u8::from(channel), message),
+            _ => continue,
+        };
+        let ch = channel as usize;
+        match message {
+            MidiMessage::ProgramChange { program } => programs[ch] = program.into(),
+            MidiMessage::NoteOn { key, vel } if u8::from(vel) > 0 => {
+                let id: (u8, u8) = (channel, key.into());
+                // Re-striking a key cuts off any pedalled note on it.
+                for note in held.remove(&id).unwrap_or_default() {
+                    finish(&mut notes, id, note, now);
+                }
+                open.entry(id).or_insert_with(VecDeque::new).push_back((
+                    now,
+                    vel.into(),
+                    programs[ch],
+                ));
+            }
+            // Most files end notes with a velocity-zero note-on rather than a note-off.
+            MidiMessage::NoteOn { key, .. } | MidiMessage::NoteOff { key, .. } => {
+                let id: (u8, u8) = (channel, key.into());
+                let note = open.get_mut(&id).and_then(|q| match opts.order {
+                    PairingOrder::Fifo => q.pop_front(),
+                    PairingOrder::Lifo => q.pop_back(),
+                });
+                match note {
+                    Some(note) if opts.sustain && pedal[ch] => {
+                        held.entry(id).or_insert_with(Vec::new).push(note)
+                    }
+                    Some(note) => finish(&mut notes, id, note, now),
+                    None => (),
+                }
+            }
+            MidiMessage::Controller { controller, value } => {
+                let controller: u8 = controller.into();
+                let value: u8 = value.into();
+                if controller == SUSTAIN_CONTROLLER {
+                    pedal[ch] = value >= 64;
+                    if !pedal[ch] {
+                        let released: Vec<(u8, u8)> =
+                            held.keys().filter(|i| i.0 == channel).cloned().collect();
+                        for id in released {
+                            for note in held.remove(&id).unwrap_or_default() {
+                                finish(&mut notes, id, note, now);
+                            }
+                        }
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    // Anything left dangling ends with the track.
+    for (id, queue) in open.into_iter() {
+        for note in queue {
+            finish(&mut notes, id, note, now);
+        }
+    }
+    for (id, pending) in held.into_iter() {
+        for note in pending {
+            finish(&mut notes, id, note, now);
+        }
+    }
+    notes.sort_by_key(|i| (i.start, i.key));
+    notes
+}
+
+/// Find when the sustain pedal is held down on each channel of one track,
+/// as `(channel, start, end)` in absolute ticks.
+pub fn pedal_spans(events: &[Event<'_>]) -> Vec<(u8, u32, u32)> {
+    let mut spans = vec![];
+    let mut down: [Option<u32>; 16] = [None; 16];
+    let mut now: u32 = 0;
+    for ev in events {
+        now = ev.delta.into();
+        if let EventKind::Midi {
+            channel,
+            message: MidiMessage::Controller { controller, value },
+        } = ev.kind
+        {
+            let channel: u8 = channel.into();
+            let ch = channel as usize;
+            if u8::from(controller) != SUSTAIN_CONTROLLER {
+                continue;
+            }
+            match (down[ch], u8::from(value) >= 64) {
+                (None, true) => down[ch] = Some(now),
+                (Some(start), false) => {
+                    spans.push((channel, start, now));
+                    down[ch] = None;
+                }
+                _ => (),
+            }
+        }
+    }
+    for (ch, start) in down.iter().enumerate() {
+        if let Some(start) = start {
+            spans.push((ch as u8, *start, now));
+        }
+    }
+    spans.sort_by_key(|i| i.1);
+    spans
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn midi(tick: u32, message: MidiMessage) -> Event<'static> {
+        Event {
+            delta: tick.into(),
+            kind: EventKind::Midi {
+                channel: 0u8.into(),
+                message,
+            },
+        }
+    }
+
+    fn on(tick: u32, key: u8) -> Event<'static> {
+        midi(
+            tick,
+            MidiMessage::NoteOn {
+                key: key.into(),
+                vel: 100u8.into(),
+            },
+        )
+    }
+
+    /// Ends a note the way most files do, with a velocity-zero note-on.
+    fn off(tick: u32, key: u8) -> Event<'static> {
+        midi(
+            tick,
+            MidiMessage::NoteOn {
+                key: key.into(),
+                vel: 0u8.into(),
+            },
+        )
+    }
+
+    fn pedal(tick: u32, value: u8) -> Event<'static> {
+        midi(
+            tick,
+            MidiMessage::Controller {
+                controller: SUSTAIN_CONTROLLER.into(),
+                value: value.into(),
+            },
+        )
+    }
+
+    fn spans(notes: &[RawNote]) -> Vec<(u32, u32)> {
+        notes.iter().map(|i| (i.start, i.end)).collect()
+    }
+
+    fn options(order: PairingOrder, sustain: bool) -> PairingOptions {
+        PairingOptions { order, sustain }
+    }
+
+    /// The same key struck twice before either is released.
+    fn overlapping() -> Vec<Event<'static>> {
+        vec![on(0, 60), on(10, 60), off(20, 60), off(30, 60)]
+    }
+
+    #[test]
+    fn fifo_ends_the_oldest_note_first() {
+        let notes = pair_notes(&overlapping(), options(PairingOrder::Fifo, false));
+        assert_eq!(spans(&notes), vec![(0, 20), (10, 30)]);
+    }
+
+    #[test]
+    fn lifo_ends_the_newest_note_first() {
+        let notes = pair_notes(&overlapping(), options(PairingOrder::Lifo, false));
+        assert_eq!(spans(&notes), vec![(0, 30), (10, 20)]);
+    }
+
+    #[test]
+    fn note_off_messages_end_notes() {
+        let events = vec![
+            on(0, 60),
+            midi(
+                15,
+                MidiMessage::NoteOff {
+                    key: 60u8.into(),
+                    vel: 64u8.into(),
+                },
+            ),
+        ];
+        let notes = pair_notes(&events, PairingOptions::default());
+        assert_eq!(spans(&notes), vec![(0, 15)]);
+        assert_eq!(notes[0].vel, 100);
+    }
+
+    #[test]
+    fn sustain_holds_notes_until_the_pedal_comes_up() {
+        let events = vec![on(0, 60), pedal(5, 127), off(10, 60), pedal(40, 0)];
+        let held = pair_notes(&events, options(PairingOrder::Fifo, true));
+        assert_eq!(spans(&held), vec![(0, 40)]);
+        let ignored = pair_notes(&events, options(PairingOrder::Fifo, false));
+        assert_eq!(spans(&ignored), vec![(0, 10)]);
+    }
+
+    #[test]
+    fn restriking_a_pedalled_key_ends_the_old_note() {
+        let events = vec![
+            on(0, 60),
+            pedal(5, 127),
+            off(10, 60),
+            on(20, 60),
+            pedal(30, 0),
+            off(50, 60),
+        ];
+        let notes = pair_notes(&events, options(PairingOrder::Fifo, true));
+        assert_eq!(spans(&notes), vec![(0, 20), (20, 50)]);
+    }
+
+    #[test]
+    fn unfinished_notes_end_with_the_track() {
+        let events = vec![on(0, 60), on(5, 64), off(10, 60), pedal(25, 0)];
+        let notes = pair_notes(&events, PairingOptions::default());
+        assert_eq!(spans(&notes), vec![(0, 10), (5, 25)]);
+    }
+
+    #[test]
+    fn pedal_spans_cover_each_press() {
+        let events = vec![pedal(0, 127), pedal(5, 100), pedal(10, 0), pedal(20, 64)];
+        assert_eq!(pedal_spans(&events), vec![(0, 0, 10), (0, 20, 20)]);
+    }
+}
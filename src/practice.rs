@@ -0,0 +1,378 @@
+use std::time::Duration;
+use std::vec::Vec;
+
+use crate::clock::SongClock;
+use crate::song::Tile;
+use crate::timing::TempoMap;
+
+/// Notes starting this close together are treated as one chord.
+pub const CHORD_SPREAD: Duration = Duration::from_millis(30);
+
+/// How long before a chord is due a key press still counts towards it.
+const EARLY_PRESS: Duration = Duration::from_millis(150);
+
+/// Stops the song at each chord until the player has pressed all of its notes.
+pub struct WaitMode {
+    pub enabled: bool,
+    /// How many wrong notes may be pressed alongside a chord before it has to be replayed.
+    pub tolerance: u32,
+    groups: Vec<(Duration, Vec<u8>)>,
+    next: usize,
+    /// Notes pressed recently, with the song time they were pressed at.
+    struck: Vec<(u8, Duration)>,
+}
+
+impl WaitMode {
+    pub fn new() -> Self {
+        Self {
+            enabled: false,
+            tolerance: 0,
+            groups: vec![],
+            next: 0,
+            struck: vec![],
+        }
+    }
+
+    /// Split `tiles` into chords. `tiles` must be sorted by start time.
+    pub fn load(&mut self, tiles: &[Tile]) {
+        self.groups.clear();
+        for tile in tiles {
+            match self.groups.last_mut() {
+                Some((start, notes)) if tile.start <= *start + CHORD_SPREAD => {
+                    if !notes.contains(&tile.note) {
+                        notes.push(tile.note);
+                    }
+                }
+                _ => self.groups.push((tile.start, vec![tile.note])),
+            }
+        }
+        self.seek(Duration::from_secs(0));
+    }
+
+    /// Wait for the first chord at or after `pos` next.
+    pub fn seek(&mut self, pos: Duration) {
+        self.next = self.groups.iter().take_while(|i| i.0 < pos).count();
+        self.struck.clear();
+    }
+
+    pub fn note_on(&mut self, note: u8, at: Duration) {
+        self.struck.push((note, at));
+    }
+
+    /// The chord currently being waited on, if any.
+    pub fn waiting_for(&self, clock: &SongClock) -> Option<&[u8]> {
+        match self.groups.get(self.next) {
+            Some((_, notes)) if self.enabled && clock.is_held() => Some(notes),
+            _ => None,
+        }
+    }
+
+    /// Release the clock once every note of the chord has been struck. Keys still held
+    /// from earlier don't count, so each chord has to be played afresh.
+    pub fn update(&mut self, clock: &mut SongClock) {
+        let (start, notes) = match self.groups.get(self.next) {
+            Some(group) if self.enabled => group,
+            _ => {
+                clock.hold_at(None);
+                return;
+            }
+        };
+        clock.hold_at(Some(*start));
+
+        let since = start.checked_sub(EARLY_PRESS).unwrap_or_default();
+        self.struck.retain(|i| i.1 >= since);
+        if !clock.is_held() {
+            return;
+        }
+        let satisfied = notes.iter().all(|&n| self.struck.iter().any(|i| i.0 == n));
+        let wrong = self.struck.iter().filter(|i| !notes.contains(&i.0)).count() as u32;
+        if wrong > self.tolerance {
+            // Too many mistakes, so the chord has to be played again.
+            self.struck.clear();
+        } else if satisfied {
+            self.next += 1;
+            self.struck.clear();
+            clock.hold_at(self.groups.get(self.next).map(|i| i.0));
+        }
+    }
+}
+
+/// A passage of the song to play over and over.
+pub struct LoopRegion {
+    pub enabled: bool,
+    pub start: Duration,
+    pub end: Duration,
+    /// Measures to play before `start` on each pass. Nothing played in them is judged.
+    pub count_in: u32,
+    /// Added to the playback rate after each pass without mistakes.
+    pub speed_step: f32,
+    pub max_rate: f32,
+    pub passes: u32,
+    pub clean_passes: u32,
+}
+
+impl LoopRegion {
+    pub fn new() -> Self {
+        Self {
+            enabled: false,
+            start: Duration::from_secs(0),
+            end: Duration::from_secs(0),
+            count_in: 0,
+            speed_step: 0.0,
+            max_rate: 1.0,
+            passes: 0,
+            clean_passes: 0,
+        }
+    }
+
+    /// Loop over the measures `first` to `last` inclusive, counting from one.
+    pub fn set_measures(&mut self, tempo_map: &TempoMap, first: u32, last: u32) {
+        let first = first.max(1);
+        self.start = tempo_map.measure_start(first - 1);
+        self.end = tempo_map.measure_start(last.max(first));
+    }
+
+    /// Where each pass begins, including the count-in.
+    pub fn restart_point(&self, tempo_map: &TempoMap) -> Duration {
+        let measure = tempo_map.measure_at(self.start);
+        if self.count_in == 0 {
+            self.start
+        } else {
+            tempo_map.measure_start(measure.saturating_sub(self.count_in))
+        }
+    }
+
+    pub fn in_count_in(&self, pos: Duration) -> bool {
+        self.enabled && self.count_in > 0 && pos < self.start
+    }
+
+    /// Beats left to count before `start`, while in the count-in.
+    pub fn beats_left(&self, tempo_map: &TempoMap, pos: Duration) -> Option<u32> {
+        if !self.in_count_in(pos) {
+            return None;
+        }
+        let target = tempo_map.measure_at(self.start);
+        let measure = tempo_map.measure_at(pos);
+        let begin = tempo_map.measure_start(measure);
+        let length = tempo_map.measure_start(measure + 1).checked_sub(begin);
+        let beats = tempo_map.beats_in_measure(measure);
+        let beat = match length {
+            Some(length) if length > Duration::from_secs(0) => {
+                let into = pos.checked_sub(begin).unwrap_or_default();
+                (into.as_secs_f32() / length.as_secs_f32() * beats as f32) as u32
+            }
+            _ => 0,
+        };
+        let later: u32 = (measure + 1..target)
+            .map(|i| tempo_map.beats_in_measure(i))
+            .sum();
+        Some(beats.saturating_sub(beat) + later)
+    }
+
+    pub fn should_wrap(&self, pos: Duration) -> bool {
+        self.enabled && self.end > self.start && pos >= self.end
+    }
+
+    /// Record a finished pass, returning the playback rate to use for the next one.
+    pub fn finish_pass(&mut self, clean: bool, rate: f32) -> f32 {
+        self.passes += 1;
+        if clean {
+            self.clean_passes += 1;
+            if self.speed_step > 0.0 && rate < self.max_rate {
+                return (rate + self.speed_step).min(self.max_rate);
+            }
+        }
+        rate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    fn paused_at(pos: Duration) -> SongClock {
+        let mut clock = SongClock::new();
+        clock.pause();
+        clock.seek(pos);
+        clock
+    }
+
+    fn wait_mode(tiles: &[Tile]) -> WaitMode {
+        let mut wait = WaitMode::new();
+        wait.enabled = true;
+        wait.load(tiles);
+        wait
+    }
+
+    fn waiting_for(wait: &WaitMode, clock: &SongClock) -> Option<Vec<u8>> {
+        wait.waiting_for(clock).map(|i| i.to_vec())
+    }
+
+    /// C major at one second, then G at two.
+    fn chords() -> Vec<Tile> {
+        vec![
+            Tile::test(60, 1000, 500),
+            Tile::test(64, 1000, 500),
+            Tile::test(67, 2000, 500),
+        ]
+    }
+
+    #[test]
+    fn notes_within_the_chord_spread_are_grouped() {
+        let wait = wait_mode(&[
+            Tile::test(60, 0, 100),
+            Tile::test(60, 10, 100),
+            Tile::test(64, 20, 100),
+            Tile::test(67, 30, 100),
+            Tile::test(72, 31, 100),
+        ]);
+        assert_eq!(
+            wait.groups,
+            vec![(ms(0), vec![60, 64, 67]), (ms(31), vec![72])]
+        );
+    }
+
+    #[test]
+    fn holds_until_every_note_of_the_chord_is_struck() {
+        let mut wait = wait_mode(&chords());
+        let mut clock = paused_at(ms(1000));
+        wait.update(&mut clock);
+        assert_eq!(waiting_for(&wait, &clock), Some(vec![60, 64]));
+
+        wait.note_on(60, ms(1000));
+        wait.update(&mut clock);
+        assert_eq!(waiting_for(&wait, &clock), Some(vec![60, 64]));
+
+        wait.note_on(64, ms(1000));
+        wait.update(&mut clock);
+        // Held at the next chord now, which the clock hasn't reached.
+        assert_eq!(waiting_for(&wait, &clock), None);
+        clock.seek(ms(2000));
+        assert_eq!(waiting_for(&wait, &clock), Some(vec![67]));
+    }
+
+    #[test]
+    fn wrong_notes_past_the_tolerance_mean_playing_it_again() {
+        let mut wait = wait_mode(&chords());
+        let mut clock = paused_at(ms(1000));
+        for &note in [61, 60, 64].iter() {
+            wait.note_on(note, ms(1000));
+        }
+        wait.update(&mut clock);
+        assert_eq!(waiting_for(&wait, &clock), Some(vec![60, 64]));
+        wait.note_on(60, ms(1000));
+        wait.note_on(64, ms(1000));
+        wait.update(&mut clock);
+        assert_eq!(waiting_for(&wait, &clock), None);
+
+        let mut lenient = wait_mode(&chords());
+        lenient.tolerance = 1;
+        let mut clock = paused_at(ms(1000));
+        for &note in [61, 60, 64].iter() {
+            lenient.note_on(note, ms(1000));
+        }
+        lenient.update(&mut clock);
+        assert_eq!(waiting_for(&lenient, &clock), None);
+    }
+
+    #[test]
+    fn each_chord_has_to_be_struck_afresh() {
+        let mut wait = wait_mode(&[Tile::test(60, 1000, 500), Tile::test(60, 2000, 500)]);
+        let mut clock = paused_at(ms(1000));
+        // Long before the chord is due, so it doesn't count.
+        wait.note_on(60, ms(500));
+        wait.update(&mut clock);
+        assert_eq!(waiting_for(&wait, &clock), Some(vec![60]));
+
+        wait.note_on(60, ms(1000));
+        wait.update(&mut clock);
+        clock.seek(ms(2000));
+        wait.update(&mut clock);
+        // The strike for the first chord doesn't carry over to the second.
+        assert_eq!(waiting_for(&wait, &clock), Some(vec![60]));
+        wait.note_on(60, ms(1900));
+        wait.update(&mut clock);
+        assert_eq!(waiting_for(&wait, &clock), None);
+    }
+
+    #[test]
+    fn turning_it_off_releases_the_clock() {
+        let mut wait = wait_mode(&chords());
+        let mut clock = paused_at(ms(1000));
+        wait.update(&mut clock);
+        assert!(clock.is_held());
+        wait.enabled = false;
+        wait.update(&mut clock);
+        assert!(!clock.is_held());
+        assert_eq!(waiting_for(&wait, &clock), None);
+    }
+
+    #[test]
+    fn seeking_waits_for_the_next_chord_from_there() {
+        let mut wait = wait_mode(&chords());
+        wait.seek(ms(1500));
+        let mut clock = paused_at(ms(2000));
+        wait.update(&mut clock);
+        assert_eq!(waiting_for(&wait, &clock), Some(vec![67]));
+    }
+
+    /// 4/4 at 120 bpm, so every measure is two seconds long.
+    fn tempo_map() -> TempoMap {
+        TempoMap::new(midly::Timing::Metrical(480u16.into()), &[])
+    }
+
+    #[test]
+    fn loops_cover_whole_measures() {
+        let map = tempo_map();
+        let mut region = LoopRegion::new();
+        region.set_measures(&map, 2, 3);
+        assert_eq!((region.start, region.end), (ms(2000), ms(6000)));
+        // Measures count from one, and the end can't come before the start.
+        region.set_measures(&map, 0, 0);
+        assert_eq!((region.start, region.end), (ms(0), ms(2000)));
+        region.set_measures(&map, 3, 1);
+        assert_eq!((region.start, region.end), (ms(4000), ms(6000)));
+    }
+
+    #[test]
+    fn passes_restart_at_the_count_in() {
+        let map = tempo_map();
+        let mut region = LoopRegion::new();
+        region.enabled = true;
+        region.set_measures(&map, 3, 4);
+        assert_eq!(region.restart_point(&map), ms(4000));
+        region.count_in = 1;
+        assert_eq!(region.restart_point(&map), ms(2000));
+        assert!(region.in_count_in(ms(3000)));
+        assert!(!region.in_count_in(ms(4000)));
+        assert_eq!(region.beats_left(&map, ms(2000)), Some(4));
+        assert_eq!(region.beats_left(&map, ms(3500)), Some(1));
+        assert_eq!(region.beats_left(&map, ms(4000)), None);
+        // There's only so much song before the loop to count in with.
+        region.count_in = 5;
+        assert_eq!(region.restart_point(&map), ms(0));
+
+        assert!(!region.should_wrap(ms(7999)));
+        assert!(region.should_wrap(ms(8000)));
+    }
+
+    #[test]
+    fn clean_passes_step_the_rate_up_to_the_limit() {
+        let mut region = LoopRegion::new();
+        region.speed_step = 0.25;
+        region.max_rate = 1.0;
+        assert!((region.finish_pass(true, 0.5) - 0.75).abs() < 1e-6);
+        assert!((region.finish_pass(false, 0.75) - 0.75).abs() < 1e-6);
+        assert!((region.finish_pass(true, 0.9) - 1.0).abs() < 1e-6);
+        assert!((region.finish_pass(true, 1.0) - 1.0).abs() < 1e-6);
+        assert_eq!((region.passes, region.clean_passes), (4, 3));
+
+        // With no step the rate never changes.
+        let mut fixed = LoopRegion::new();
+        assert!((fixed.finish_pass(true, 0.5) - 0.5).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,278 @@
+use ggez::event::KeyCode;
+use std::sync::mpsc::Sender;
+use std::time::Instant;
+use std::vec::Vec;
+
+use crate::input::{InputEvent, InputKind};
+
+/// Two rows of keys laid out like a piano, as semitones above the bottom C.
+pub const DEFAULT_MAP: [(KeyCode, u8); 30] = [
+    (KeyCode::Z, 0),
+    (KeyCode::S, 1),
+    (KeyCode::X, 2),
+    (KeyCode::D, 3),
+    (KeyCode::C, 4),
+    (KeyCode::V, 5),
+    (KeyCode::G, 6),
+    (KeyCode::B, 7),
+    (KeyCode::H, 8),
+    (KeyCode::N, 9),
+    (KeyCode::J, 10),
+    (KeyCode::M, 11),
+    (KeyCode::Comma, 12),
+    (KeyCode::Q, 12),
+    (KeyCode::Key2, 13),
+    (KeyCode::W, 14),
+    (KeyCode::Key3, 15),
+    (KeyCode::E, 16),
+    (KeyCode::R, 17),
+    (KeyCode::Key5, 18),
+    (KeyCode::T, 19),
+    (KeyCode::Key6, 20),
+    (KeyCode::Y, 21),
+    (KeyCode::Key7, 22),
+    (KeyCode::U, 23),
+    (KeyCode::I, 24),
+    (KeyCode::Key9, 25),
+    (KeyCode::O, 26),
+    (KeyCode::Key0, 27),
+    (KeyCode::P, 28),
+];
+
+const OCTAVE_DOWN: KeyCode = KeyCode::Minus;
+const OCTAVE_UP: KeyCode = KeyCode::Equals;
+
+/// Plays notes from the computer keyboard, through the same path as a MIDI device.
+pub struct QwertyPiano {
+    pub enabled: bool,
+    /// The octave of the bottom row, where C4 is middle C.
+    pub octave: i8,
+    pub velocity: u8,
+    /// Which key plays which note, in semitones above the bottom C.
+    pub map: Vec<(KeyCode, u8)>,
+    /// A note waiting for the next key pressed to be assigned to it.
+    pub learning: Option<u8>,
+    /// Keys held down, with the note each one started.
+    held: Vec<(KeyCode, u8)>,
+    sender: Sender<InputEvent>,
+}
+
+impl QwertyPiano {
+    pub fn new(sender: Sender<InputEvent>) -> Self {
+        Self {
+            enabled: false,
+            octave: 3,
+            velocity: 80,
+            map: DEFAULT_MAP.to_vec(),
+            learning: None,
+            held: vec![],
+            sender,
+        }
+    }
+
+    fn send(&self, kind: InputKind) {
+        let _ = self.sender.send(InputEvent {
+            time: Instant::now(),
+            kind,
+        });
+    }
+
+    /// Make `keycode` play the note `offset` semitones above the bottom C instead of
+    /// whatever it played before.
+    pub fn assign(&mut self, keycode: KeyCode, offset: u8) {
+        self.map.retain(|i| i.0 != keycode);
+        self.map.push((keycode, offset));
+    }
+
+    /// Handle a key press, returning whether it was used.
+    pub fn key_down(&mut self, keycode: KeyCode, repeat: bool) -> bool {
+        if !self.enabled {
+            return false;
+        }
+        if let Some(offset) = self.learning.take() {
+            // The octave keys and Escape can't be reassigned, so they cancel instead.
+            if ![OCTAVE_DOWN, OCTAVE_UP, KeyCode::Escape].contains(&keycode) {
+                self.assign(keycode, offset);
+            }
+            return true;
+        }
+        match keycode {
+            OCTAVE_DOWN => self.octave = (self.octave - 1).max(0),
+            OCTAVE_UP => self.octave = (self.octave + 1).min(7),
+            _ => {
+                let offset = match self.map.iter().find(|i| i.0 == keycode) {
+                    Some(&(_, offset)) => offset,
+                    None => return false,
+                };
+                if repeat || self.held.iter().any(|i| i.0 == keycode) {
+                    return true;
+                }
+                let key = (self.octave as i32 + 1) * 12 + offset as i32;
+                if key > 127 {
+                    return true;
+                }
+                let key = key as u8;
+                self.held.push((keycode, key));
+                self.send(InputKind::NoteOn {
+                    channel: 0,
+                    key,
+                    vel: self.velocity,
+                });
+            }
+        }
+        true
+    }
+
+    pub fn key_up(&mut self, keycode: KeyCode) {
+        if let Some(idx) = self.held.iter().position(|i| i.0 == keycode) {
+            let (_, key) = self.held.remove(idx);
+            self.send(InputKind::NoteOff {
+                channel: 0,
+                key,
+                vel: 0,
+            });
+        }
+    }
+
+    /// Let go of every key, such as when the computer keyboard is switched off.
+    pub fn release_all(&mut self) {
+        for (_, key) in std::mem::take(&mut self.held) {
+            self.send(InputKind::NoteOff {
+                channel: 0,
+                key,
+                vel: 0,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::{channel, Receiver};
+
+    fn piano() -> (QwertyPiano, Receiver<InputEvent>) {
+        let (sender, receiver) = channel();
+        let mut piano = QwertyPiano::new(sender);
+        piano.enabled = true;
+        (piano, receiver)
+    }
+
+    fn sent(receiver: &Receiver<InputEvent>) -> Vec<InputKind> {
+        receiver.try_iter().map(|i| i.kind).collect()
+    }
+
+    fn on(key: u8) -> InputKind {
+        InputKind::NoteOn {
+            channel: 0,
+            key,
+            vel: 80,
+        }
+    }
+
+    fn off(key: u8) -> InputKind {
+        InputKind::NoteOff {
+            channel: 0,
+            key,
+            vel: 0,
+        }
+    }
+
+    #[test]
+    fn keys_play_from_the_current_octave() {
+        let (mut piano, receiver) = piano();
+        assert!(piano.key_down(KeyCode::Z, false));
+        assert!(piano.key_down(KeyCode::Q, false));
+        assert_eq!(sent(&receiver), vec![on(48), on(60)]);
+
+        piano.key_up(KeyCode::Z);
+        piano.key_up(KeyCode::Q);
+        assert!(piano.key_down(OCTAVE_UP, false));
+        piano.key_down(KeyCode::Z, false);
+        piano.key_up(KeyCode::Z);
+        assert!(piano.key_down(OCTAVE_DOWN, false));
+        assert!(piano.key_down(OCTAVE_DOWN, false));
+        piano.key_down(KeyCode::Z, false);
+        assert_eq!(
+            sent(&receiver),
+            vec![off(48), off(60), on(60), off(60), on(36)]
+        );
+    }
+
+    #[test]
+    fn octaves_stop_at_the_ends() {
+        let (mut piano, _receiver) = piano();
+        for _ in 0..10 {
+            piano.key_down(OCTAVE_DOWN, false);
+        }
+        assert_eq!(piano.octave, 0);
+        for _ in 0..10 {
+            piano.key_down(OCTAVE_UP, false);
+        }
+        assert_eq!(piano.octave, 7);
+    }
+
+    #[test]
+    fn held_keys_are_not_repeated() {
+        let (mut piano, receiver) = piano();
+        piano.key_down(KeyCode::C, false);
+        assert!(piano.key_down(KeyCode::C, true));
+        assert!(piano.key_down(KeyCode::C, false));
+        assert_eq!(sent(&receiver), vec![on(52)]);
+        // A note that's already held keeps its key after the octave moves.
+        piano.key_down(OCTAVE_UP, false);
+        piano.key_up(KeyCode::C);
+        piano.key_up(KeyCode::C);
+        assert_eq!(sent(&receiver), vec![off(52)]);
+    }
+
+    #[test]
+    fn learning_assigns_the_next_key() {
+        let (mut piano, receiver) = piano();
+        piano.learning = Some(4);
+        assert!(piano.key_down(KeyCode::A, false));
+        assert_eq!(piano.learning, None);
+        assert!(sent(&receiver).is_empty());
+        piano.key_down(KeyCode::A, false);
+        assert_eq!(sent(&receiver), vec![on(52)]);
+
+        // Assigning a key again replaces what it played before.
+        piano.learning = Some(7);
+        piano.key_down(KeyCode::Z, false);
+        assert_eq!(piano.map.iter().filter(|i| i.0 == KeyCode::Z).count(), 1);
+        assert!(piano.map.contains(&(KeyCode::Z, 7)));
+    }
+
+    #[test]
+    fn escape_cancels_learning() {
+        let (mut piano, _receiver) = piano();
+        let map = piano.map.clone();
+        for &key in &[KeyCode::Escape, OCTAVE_UP, OCTAVE_DOWN] {
+            piano.learning = Some(4);
+            assert!(piano.key_down(key, false));
+            assert_eq!(piano.learning, None);
+        }
+        assert_eq!(piano.map, map);
+        assert_eq!(piano.octave, 3);
+    }
+
+    #[test]
+    fn release_all_lets_go_of_every_key() {
+        let (mut piano, receiver) = piano();
+        piano.key_down(KeyCode::Z, false);
+        piano.key_down(KeyCode::X, false);
+        piano.release_all();
+        assert_eq!(sent(&receiver), vec![on(48), on(50), off(48), off(50)]);
+        piano.release_all();
+        assert!(sent(&receiver).is_empty());
+    }
+
+    #[test]
+    fn disabled_or_unmapped_keys_are_not_used() {
+        let (mut piano, receiver) = piano();
+        assert!(!piano.key_down(KeyCode::F1, false));
+        piano.enabled = false;
+        assert!(!piano.key_down(KeyCode::Z, false));
+        assert!(sent(&receiver).is_empty());
+    }
+}
@@ -1,10 +1,17 @@
 use midly::Smf;
-use midly::{Event, EventKind, MidiMessage};
+use midly::{EventKind, Format, MetaMessage};
+use std::fmt;
 use std::fs;
-use std::path::PathBuf;
-use std::time::{Duration, Instant};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use crate::midi_interpreter::{as_merged, to_abstime};
+use crate::clock::SongClock;
+use crate::hands::{self, Hand, HandMode, HandSplit};
+use crate::midi_interpreter::{instrument_name, repair_truncated, to_abstime, DRUM_CHANNEL};
+use crate::pairing::{pair_notes, pedal_spans, PairingOptions};
+use crate::timing::TempoMap;
+use crate::transform::Transform;
 
 // TODO: clean all this up.
 static mut DELTA_T: Duration = Duration::from_millis(2000 as u64);
@@ -21,118 +28,436 @@ pub fn set_deltat(ms: impl Into<u64>) {
 
 #[derive(Debug, Copy, Clone)]
 pub struct Tile {
+    /// The MIDI key to play.
     pub note: u8,
     pub start: Duration,
     pub length: Duration,
+    /// How hard the note is written to be played, from 1 to 127.
+    pub velocity: u8,
+    pub track: usize,
+    pub channel: u8,
+    pub program: u8,
+    pub hand: Hand,
 }
 
 impl Tile {
-    pub fn time_to_activate(&self, reference: &Instant) -> Duration {
-        (*reference + self.start).saturating_duration_since(Instant::now())
+    pub fn end(&self) -> Duration {
+        self.start + self.length
     }
 
-    pub fn time_to_die(&self, reference: &Instant) -> Duration {
-        (*reference + self.start + self.length).saturating_duration_since(Instant::now())
+    pub fn time_to_activate(&self, clock: &SongClock) -> Duration {
+        self.start.checked_sub(clock.position()).unwrap_or_default()
     }
 
-    pub fn vertical_position(&self, reference: &Instant, height: f32) -> f32 {
-        height * (1.0 - self.time_to_die(reference).as_secs_f32() / deltat().as_secs_f32())
+    pub fn time_to_die(&self, clock: &SongClock) -> Duration {
+        self.end().checked_sub(clock.position()).unwrap_or_default()
     }
 
-    pub fn is_alive(&self, reference: &Instant) -> bool {
-        (*reference + self.start - deltat())
-            .checked_duration_since(Instant::now())
-            .is_none()
+    pub fn vertical_position(&self, clock: &SongClock, height: f32) -> f32 {
+        height * (1.0 - self.time_to_die(clock).as_secs_f32() / clock.window().as_secs_f32())
     }
 
-    pub fn is_dead(&self, reference: &Instant) -> bool {
-        (*reference + self.start + self.length)
-            .checked_duration_since(Instant::now())
-            .is_none()
+    pub fn is_alive(&self, clock: &SongClock) -> bool {
+        self.start <= clock.position() + clock.window()
     }
 
-    pub fn in_scope(&self, reference: &Instant) -> bool {
-        self.is_alive(reference) && !self.is_dead(reference)
+    pub fn is_dead(&self, clock: &SongClock) -> bool {
+        self.end() <= clock.position()
     }
 
-    pub fn vertical_height(&self, height: f32) -> f32 {
-        height * self.length.as_secs_f32() / deltat().as_secs_f32()
+    pub fn in_scope(&self, clock: &SongClock) -> bool {
+        self.is_alive(clock) && !self.is_dead(clock)
     }
+
+    pub fn vertical_height(&self, clock: &SongClock, height: f32) -> f32 {
+        height * self.length.as_secs_f32() / clock.window().as_secs_f32()
+    }
+}
+
+#[cfg(test)]
+impl Tile {
+    /// A right-hand tile on the first track and channel, for tests.
+    pub fn test(note: u8, start_ms: u64, length_ms: u64) -> Self {
+        Self {
+            note,
+            start: Duration::from_millis(start_ms),
+            length: Duration::from_millis(length_ms),
+            velocity: 100,
+            track: 0,
+            channel: 0,
+            program: 0,
+            hand: Hand::Right,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SongError {
+    Io(io::Error),
+    Parse(midly::Error),
+    UnsupportedFormat(&'static str),
+    /// A note the piano doesn't have, as a MIDI key number.
+    NoteOutOfRange(u8),
+    Empty,
+}
+
+impl fmt::Display for SongError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SongError::Io(e) => write!(f, "Unable to read the file: {}", e),
+            SongError::Parse(e) => write!(f, "Unable to parse the file: {}", e),
+            SongError::UnsupportedFormat(what) => write!(f, "Unsupported file: {}", what),
+            SongError::NoteOutOfRange(key) => {
+                write!(f, "Note {} is outside the piano's range", key)
+            }
+            SongError::Empty => write!(f, "The song has no notes"),
+        }
+    }
+}
+
+impl std::error::Error for SongError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SongError::Io(e) => Some(e),
+            // midly's errors are `failure::Fail`s rather than standard errors,
+            // so their message is all that can be passed on.
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SongError {
+    fn from(e: io::Error) -> Self {
+        SongError::Io(e)
+    }
+}
+
+/// Lowest and highest MIDI keys on an 88-key piano.
+pub const LOWEST_KEY: u8 = 21;
+pub const HIGHEST_KEY: u8 = 108;
+
+/// Check that `val` is a key found on a piano.
+pub fn piano_key<T: Into<u8>>(val: T) -> Result<u8, SongError> {
+    let key: u8 = val.into();
+    if key < LOWEST_KEY || key > HIGHEST_KEY {
+        Err(SongError::NoteOutOfRange(key))
+    } else {
+        Ok(key)
+    }
+}
+
+/// A stretch of the song with the sustain pedal held down.
+#[derive(Debug, Copy, Clone)]
+pub struct PedalSpan {
+    pub start: Duration,
+    pub end: Duration,
+    pub track: usize,
+    pub channel: u8,
 }
 
-pub fn key_index<T: Into<u8>>(val: T) -> usize {
-    let idx: u8 = val.into();
-    idx as usize - 21
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PartRole {
+    /// Shown as tiles for the player to play.
+    Play,
+    /// Heard through the synth but not shown.
+    Accompany,
+    Mute,
+}
+
+/// The notes of one channel within one track.
+#[derive(Debug, Clone)]
+pub struct Part {
+    pub track: usize,
+    pub channel: u8,
+    pub name: String,
+    pub program: u8,
+    pub note_count: usize,
+    pub role: PartRole,
+}
+
+impl Part {
+    pub fn instrument(&self) -> &'static str {
+        instrument_name(self.channel, self.program)
+    }
+
+    fn contains(&self, tile: &Tile) -> bool {
+        tile.track == self.track && tile.channel == self.channel
+    }
 }
 
 pub struct Song {
     pub target: PathBuf,
+    pub parts: Vec<Part>,
+    pub hand_split: HandSplit,
+    pub hand_mode: HandMode,
+    /// How the notes are changed to suit the player's keyboard.
+    pub transform: Transform,
+    /// Every note in the file, whatever its part's role.
+    all_tiles: Vec<Tile>,
+    /// The notes the player has to play.
     pub tiles: Vec<Tile>,
+    pub accompaniment: Vec<Tile>,
+    all_pedals: Vec<PedalSpan>,
+    /// Pedal markings for the parts being played.
+    pub pedals: Vec<PedalSpan>,
+    pub tempo_map: TempoMap,
+    /// Problems that were worked around while loading.
+    pub warnings: Vec<String>,
     pending_tiles: Vec<Tile>,
     pub active_tiles: Vec<Tile>,
 }
 
 impl Song {
-    pub fn new<T: Into<PathBuf>>(tgt: T) -> Self {
+    pub fn new<T: Into<PathBuf>>(tgt: T, pairing: PairingOptions) -> Result<Self, SongError> {
         let target = tgt.into();
-        let mut tiles = Self::process(target.clone());
-        tiles.sort_by_key(|i| i.start);
-        let mut pending_tiles = tiles.clone();
-        let len = tiles.len();
-        pending_tiles.reverse();
-        Self {
-            target,
-            tiles,
-            pending_tiles,
-            active_tiles: Vec::with_capacity(len),
+        let mut warnings = vec![];
+        let (mut all_tiles, mut parts, all_pedals, tempo_map) =
+            Self::process(&target, pairing, &mut warnings)?;
+        all_tiles.sort_by_key(|i| i.start);
+
+        // Play the piano parts if there are any, otherwise everything but the drums.
+        let is_piano = |p: &Part| p.channel != DRUM_CHANNEL && p.program < 8;
+        let any_piano = parts.iter().any(is_piano);
+        for part in parts.iter_mut() {
+            part.role = if part.channel == DRUM_CHANNEL || (any_piano && !is_piano(part)) {
+                PartRole::Accompany
+            } else {
+                PartRole::Play
+            };
         }
+
+        let mut song = Self {
+            target,
+            parts,
+            hand_split: HandSplit::ByPart,
+            hand_mode: HandMode::Both,
+            transform: Transform::default(),
+            all_tiles,
+            tiles: vec![],
+            accompaniment: vec![],
+            all_pedals,
+            pedals: vec![],
+            tempo_map,
+            warnings,
+            pending_tiles: vec![],
+            active_tiles: vec![],
+        };
+        song.apply_roles();
+        Ok(song)
+    }
+
+    /// The role of the part `tile` belongs to. Tiles outside every part are muted.
+    fn role_of(&self, tile: &Tile) -> PartRole {
+        self.parts
+            .iter()
+            .find(|p| p.contains(tile))
+            .map(|p| p.role)
+            .unwrap_or(PartRole::Mute)
+    }
+
+    /// The notes of the parts being played, as written in the file.
+    pub fn source_tiles(&self) -> Vec<Tile> {
+        self.all_tiles
+            .iter()
+            .filter(|i| self.role_of(i) == PartRole::Play)
+            .cloned()
+            .collect()
+    }
+
+    /// Rebuild the played and accompaniment tiles after the parts' roles, hand settings or
+    /// transform change. The pending and active tiles are cleared, so the song has to be
+    /// seeked afterwards to carry on from the current position.
+    pub fn apply_roles(&mut self) {
+        let transform = self.transform;
+        let mut played: Vec<Tile> = self
+            .source_tiles()
+            .iter()
+            .filter_map(|i| transform.apply(i))
+            .collect();
+        let parts = &self.parts;
+        hands::assign(&mut played, parts, self.hand_split);
+        let mode = self.hand_mode;
+        // Whichever hand isn't being practised is played for the player.
+        let (tiles, auto): (Vec<Tile>, Vec<Tile>) =
+            played.into_iter().partition(|i| mode.plays(i.hand));
+        self.tiles = tiles;
+        self.accompaniment = self
+            .all_tiles
+            .iter()
+            .filter(|i| self.role_of(i) == PartRole::Accompany)
+            .filter_map(|i| transform.transpose(i))
+            .chain(auto)
+            .collect();
+        self.accompaniment.sort_by_key(|i| i.start);
+        // Pedalling is kept unless its part is muted, since it's often written on a
+        // channel with no notes of its own. The same pedalling on several channels counts once.
+        self.pedals = self
+            .all_pedals
+            .iter()
+            .filter(|i| {
+                !parts.iter().any(|p| {
+                    p.track == i.track && p.channel == i.channel && p.role == PartRole::Mute
+                })
+            })
+            .cloned()
+            .collect();
+        self.pedals.sort_by_key(|i| (i.start, i.end));
+        self.pedals.dedup_by_key(|i| (i.start, i.end));
+        self.pending_tiles = self.tiles.iter().rev().cloned().collect();
+        self.active_tiles = Vec::with_capacity(self.tiles.len());
+    }
+
+    /// Every tile that makes a sound, played or accompaniment, sorted by start time.
+    pub fn sounding_tiles(&self) -> Vec<Tile> {
+        let mut tiles: Vec<Tile> = self
+            .tiles
+            .iter()
+            .chain(self.accompaniment.iter())
+            .cloned()
+            .collect();
+        tiles.sort_by_key(|i| i.start);
+        tiles
     }
 
-    fn process(target: PathBuf) -> Vec<Tile> {
-        let contents = fs::read(target.clone()).unwrap();
-        let smf = Smf::parse(&contents).unwrap();
-        let events = to_abstime(as_merged(smf.tracks));
-        let mut starts: [Option<u32>; 128] = [None; 128];
+    fn process(
+        target: &Path,
+        pairing: PairingOptions,
+        warnings: &mut Vec<String>,
+    ) -> Result<(Vec<Tile>, Vec<Part>, Vec<PedalSpan>, TempoMap), SongError> {
+        let contents = fs::read(target)?;
+        let repaired: Vec<Vec<u8>>;
+        let smf = match Smf::parse(&contents) {
+            Ok(smf) => smf,
+            Err(e) => {
+                repaired = repair_truncated(&contents);
+                let smf = repaired
+                    .iter()
+                    .filter_map(|i| Smf::parse(i).ok())
+                    .next()
+                    .ok_or(SongError::Parse(e))?;
+                warnings.push("The file was damaged; some notes may be missing.".to_string());
+                smf
+            }
+        };
+        if let Format::Sequential = smf.header.format {
+            return Err(SongError::UnsupportedFormat(
+                "sequential (type 2) files hold several independent songs",
+            ));
+        }
+        let mut out_of_range: Vec<u8> = vec![];
+        let tempo_map = TempoMap::new(smf.header.timing, &smf.tracks);
         let mut tiles: Vec<Tile> = vec![];
-        for ev in events {
-            match ev.kind {
-                EventKind::Midi {
-                    channel: _,
-                    message,
-                } => match message {
-                    MidiMessage::NoteOn { key, vel: _ } => {
-                        let idx = key_index(key);
-                        if starts[idx] == None {
-                            starts[idx] = Some(ev.delta.into());
-                        }
-                    }
-                    MidiMessage::NoteOff { key, vel: _ } => {
-                        let idx = key_index(key);
-                        let end: u32 = ev.delta.into();
-                        if let Some(start) = starts[idx] {
-                            let length = Duration::from_millis((end - start).into());
-                            let tile = Tile {
-                                note: idx as u8,
-                                start: Duration::from_millis(start.into()),
-                                length,
-                            };
-                            tiles.push(tile);
-                            starts[idx] = None;
-                        }
+        let mut parts: Vec<Part> = vec![];
+        let mut pedals: Vec<PedalSpan> = vec![];
+        for (track, events) in smf.tracks.iter().enumerate() {
+            let events = to_abstime(events.clone());
+            for (channel, start, end) in pedal_spans(&events) {
+                pedals.push(PedalSpan {
+                    start: tempo_map.to_duration(start),
+                    end: tempo_map.to_duration(end),
+                    track,
+                    channel,
+                });
+            }
+            let name = events.iter().find_map(|ev| match ev.kind {
+                EventKind::Meta(MetaMessage::TrackName(raw)) => {
+                    Some(String::from_utf8_lossy(raw).trim().to_string())
+                }
+                _ => None,
+            });
+            let first_part = parts.len();
+            for raw in pair_notes(&events, pairing) {
+                let note = match piano_key(raw.key) {
+                    Ok(note) => note,
+                    Err(_) => {
+                        out_of_range.push(raw.key);
+                        continue;
                     }
-                    _ => (),
-                },
-                _ => (),
+                };
+                let start = tempo_map.to_duration(raw.start);
+                let length = tempo_map.to_duration(raw.end) - start;
+                tiles.push(Tile {
+                    note,
+                    start,
+                    length,
+                    velocity: raw.vel,
+                    track,
+                    channel: raw.channel,
+                    program: raw.program,
+                    hand: Hand::Right,
+                });
+                match parts[first_part..]
+                    .iter_mut()
+                    .find(|p| p.channel == raw.channel)
+                {
+                    Some(part) => part.note_count += 1,
+                    None => parts.push(Part {
+                        track,
+                        channel: raw.channel,
+                        name: String::new(),
+                        program: raw.program,
+                        note_count: 1,
+                        role: PartRole::Play,
+                    }),
+                }
+            }
+            let multiple = parts.len() - first_part > 1;
+            for part in parts[first_part..].iter_mut() {
+                let base = name
+                    .clone()
+                    .filter(|i| !i.is_empty())
+                    .unwrap_or_else(|| format!("Track {}", track + 1));
+                part.name = if multiple {
+                    format!("{} (channel {})", base, part.channel + 1)
+                } else {
+                    base
+                };
             }
         }
-        tiles
+        if tiles.is_empty() {
+            return Err(match out_of_range.first() {
+                Some(&key) => SongError::NoteOutOfRange(key),
+                None => SongError::Empty,
+            });
+        }
+        if !out_of_range.is_empty() {
+            warnings.push(format!(
+                "Skipped {} note(s) outside the piano's range.",
+                out_of_range.len()
+            ));
+        }
+        pedals.sort_by_key(|i| i.start);
+        Ok((tiles, parts, pedals, tempo_map))
+    }
+
+    /// When the last note of the song ends.
+    pub fn length(&self) -> Duration {
+        self.tiles.iter().map(Tile::end).max().unwrap_or_default()
+    }
+
+    /// Rebuild the pending and active tiles for the clock's current position,
+    /// which may be anywhere in the song.
+    pub fn seek(&mut self, clock: &SongClock) {
+        self.pending_tiles = self
+            .tiles
+            .iter()
+            .rev()
+            .filter(|i| !i.is_alive(clock))
+            .cloned()
+            .collect();
+        self.active_tiles = self
+            .tiles
+            .iter()
+            .filter(|i| i.in_scope(clock))
+            .cloned()
+            .collect();
     }
 
-    pub fn update(&mut self, reference: &Instant) {
+    pub fn update(&mut self, clock: &SongClock) {
         while self
             .pending_tiles
             .last()
-            .map(|i| i.is_alive(reference))
+            .map(|i| i.is_alive(clock))
             .unwrap_or(false)
         {
             self.active_tiles.push(self.pending_tiles.pop().unwrap());
@@ -141,10 +466,83 @@ impl Song {
         while self
             .active_tiles
             .get(0)
-            .map(|i| i.is_dead(reference))
+            .map(|i| i.is_dead(clock))
             .unwrap_or(false)
         {
             self.active_tiles.remove(0);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use midly::Timing;
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    /// A song of `tiles`, all in one played part.
+    fn song(tiles: Vec<Tile>) -> Song {
+        let mut song = Song {
+            target: PathBuf::new(),
+            parts: vec![Part {
+                track: 0,
+                channel: 0,
+                name: String::new(),
+                program: 0,
+                note_count: tiles.len(),
+                role: PartRole::Play,
+            }],
+            hand_split: HandSplit::ByPart,
+            hand_mode: HandMode::Both,
+            transform: Transform::default(),
+            all_tiles: tiles,
+            tiles: vec![],
+            accompaniment: vec![],
+            all_pedals: vec![],
+            pedals: vec![],
+            tempo_map: TempoMap::new(Timing::Metrical(480u16.into()), &[]),
+            warnings: vec![],
+            pending_tiles: vec![],
+            active_tiles: vec![],
+        };
+        song.apply_roles();
+        song
+    }
+
+    fn paused_at(pos: Duration) -> SongClock {
+        let mut clock = SongClock::new();
+        clock.pause();
+        clock.seek(pos);
+        clock
+    }
+
+    fn active_starts(song: &Song) -> Vec<Duration> {
+        song.active_tiles.iter().map(|i| i.start).collect()
+    }
+
+    #[test]
+    fn seeking_back_rebuilds_the_active_tiles() {
+        let mut song = song(vec![
+            Tile::test(60, 0, 500),
+            Tile::test(62, 1000, 500),
+            Tile::test(64, 3000, 500),
+            Tile::test(65, 5000, 500),
+        ]);
+        // Two seconds of song are visible above the keyboard.
+        let mut clock = paused_at(ms(4000));
+        song.seek(&clock);
+        assert_eq!(active_starts(&song), vec![ms(5000)]);
+
+        clock.seek(ms(200));
+        song.seek(&clock);
+        assert_eq!(active_starts(&song), vec![ms(0), ms(1000)]);
+
+        // Playing on from there picks up the later tiles again.
+        clock.seek(ms(2500));
+        song.update(&clock);
+        assert_eq!(active_starts(&song), vec![ms(3000)]);
+    }
+}
@@ -0,0 +1,273 @@
+use std::f32::consts::PI;
+use std::io::{self, Write};
+use std::vec::Vec;
+
+pub const SAMPLE_RATE: u32 = 44_100;
+
+// Voices beyond this steal the oldest one.
+const MAX_VOICES: usize = 48;
+
+#[derive(Debug, Copy, Clone)]
+pub struct Adsr {
+    /// Seconds to reach full level.
+    pub attack: f32,
+    /// Seconds to fall from full level to `sustain`.
+    pub decay: f32,
+    pub sustain: f32,
+    /// Seconds to fade out once released.
+    pub release: f32,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Timbre {
+    Piano,
+    ElectricPiano,
+    Organ,
+}
+
+impl Timbre {
+    pub const ALL: [Timbre; 3] = [Timbre::Piano, Timbre::ElectricPiano, Timbre::Organ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Timbre::Piano => "Piano",
+            Timbre::ElectricPiano => "Electric piano",
+            Timbre::Organ => "Organ",
+        }
+    }
+
+    fn envelope(self) -> Adsr {
+        match self {
+            Timbre::Piano => Adsr {
+                attack: 0.004,
+                decay: 2.5,
+                sustain: 0.0,
+                release: 0.25,
+            },
+            Timbre::ElectricPiano => Adsr {
+                attack: 0.002,
+                decay: 1.2,
+                sustain: 0.2,
+                release: 0.3,
+            },
+            Timbre::Organ => Adsr {
+                attack: 0.01,
+                decay: 0.05,
+                sustain: 0.9,
+                release: 0.05,
+            },
+        }
+    }
+
+    /// Harmonic number, relative amplitude and damping (per second) of each partial.
+    fn partials(self) -> &'static [(f32, f32, f32)] {
+        match self {
+            Timbre::Piano => &[
+                (1.0, 1.0, 0.6),
+                (2.0, 0.5, 1.5),
+                (3.0, 0.25, 2.5),
+                (4.0, 0.15, 3.5),
+                (5.0, 0.08, 5.0),
+            ],
+            Timbre::ElectricPiano => &[(1.0, 1.0, 0.4), (2.0, 0.2, 2.0), (7.0, 0.1, 8.0)],
+            Timbre::Organ => &[(1.0, 1.0, 0.0), (2.0, 0.6, 0.0), (4.0, 0.3, 0.0)],
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Stage {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+    Done,
+}
+
+struct Voice {
+    key: u8,
+    freq: f32,
+    gain: f32,
+    /// Brightness from velocity, scaling the upper partials.
+    bright: f32,
+    time: f32,
+    level: f32,
+    release_from: f32,
+    stage: Stage,
+    env: Adsr,
+    partials: &'static [(f32, f32, f32)],
+}
+
+impl Voice {
+    fn new(key: u8, vel: u8, timbre: Timbre) -> Self {
+        let vel = vel as f32 / 127.0;
+        Self {
+            key,
+            freq: 440.0 * 2f32.powf((key as f32 - 69.0) / 12.0),
+            gain: vel.powf(1.5),
+            bright: 0.4 + 0.6 * vel,
+            time: 0.0,
+            level: 0.0,
+            release_from: 0.0,
+            stage: Stage::Attack,
+            env: timbre.envelope(),
+            partials: timbre.partials(),
+        }
+    }
+
+    fn release(&mut self) {
+        if self.stage != Stage::Done {
+            self.release_from = self.level;
+            self.stage = Stage::Release;
+        }
+    }
+
+    fn advance_envelope(&mut self, dt: f32) {
+        let env = self.env;
+        match self.stage {
+            Stage::Attack => {
+                self.level += dt / env.attack.max(dt);
+                if self.level >= 1.0 {
+                    self.level = 1.0;
+                    self.stage = Stage::Decay;
+                }
+            }
+            Stage::Decay => {
+                self.level -= (1.0 - env.sustain) * dt / env.decay.max(dt);
+                if self.level <= env.sustain {
+                    self.level = env.sustain;
+                    self.stage = Stage::Sustain;
+                }
+            }
+            Stage::Sustain => {
+                if self.level <= 0.0 {
+                    self.stage = Stage::Done;
+                }
+            }
+            Stage::Release => {
+                self.level -= self.release_from * dt / env.release.max(dt);
+                if self.level <= 0.0 {
+                    self.level = 0.0;
+                    self.stage = Stage::Done;
+                }
+            }
+            Stage::Done => (),
+        }
+    }
+
+    fn next_sample(&mut self, dt: f32) -> f32 {
+        let mut out = 0.0;
+        for &(harmonic, amp, damping) in self.partials {
+            let freq = self.freq * harmonic;
+            if freq >= SAMPLE_RATE as f32 / 2.0 {
+                break;
+            }
+            let amp = if harmonic > 1.0 {
+                amp * self.bright
+            } else {
+                amp
+            };
+            out += amp * (-damping * self.time).exp() * (2.0 * PI * freq * self.time).sin();
+        }
+        self.time += dt;
+        self.advance_envelope(dt);
+        out * self.level * self.gain
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum SynthCommand {
+    NoteOn { key: u8, vel: u8 },
+    NoteOff { key: u8 },
+    AllNotesOff,
+    SetTimbre(Timbre),
+}
+
+/// A small polyphonic additive synthesizer producing mono samples.
+pub struct Synth {
+    voices: Vec<Voice>,
+    timbre: Timbre,
+    sample_rate: u32,
+    pub volume: f32,
+}
+
+impl Synth {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            voices: Vec::with_capacity(MAX_VOICES),
+            timbre: Timbre::Piano,
+            sample_rate,
+            volume: 0.25,
+        }
+    }
+
+    pub fn handle(&mut self, cmd: SynthCommand) {
+        match cmd {
+            SynthCommand::NoteOn { key, vel: 0 } => self.note_off(key),
+            SynthCommand::NoteOn { key, vel } => self.note_on(key, vel),
+            SynthCommand::NoteOff { key } => self.note_off(key),
+            SynthCommand::AllNotesOff => self.voices.clear(),
+            SynthCommand::SetTimbre(timbre) => self.timbre = timbre,
+        }
+    }
+
+    pub fn note_on(&mut self, key: u8, vel: u8) {
+        // Re-striking a key releases the previous voice, like a real piano's damper.
+        self.note_off(key);
+        if self.voices.len() >= MAX_VOICES {
+            let victim = self
+                .voices
+                .iter()
+                .position(|v| v.stage == Stage::Release)
+                .unwrap_or(0);
+            self.voices.remove(victim);
+        }
+        self.voices.push(Voice::new(key, vel, self.timbre));
+    }
+
+    pub fn note_off(&mut self, key: u8) {
+        self.voices
+            .iter_mut()
+            .filter(|v| v.key == key && v.stage != Stage::Release)
+            .for_each(Voice::release);
+    }
+
+    pub fn next_sample(&mut self) -> f32 {
+        let dt = 1.0 / self.sample_rate as f32;
+        let mut out = 0.0;
+        for voice in self.voices.iter_mut() {
+            out += voice.next_sample(dt);
+        }
+        self.voices.retain(|v| v.stage != Stage::Done);
+        // Soft clip so big chords don't wrap around.
+        (out * self.volume).tanh()
+    }
+
+    pub fn render(&mut self, out: &mut [f32]) {
+        for sample in out.iter_mut() {
+            *sample = self.next_sample();
+        }
+    }
+}
+
+/// Write mono `f32` samples as a 16-bit PCM WAV file.
+pub fn write_wav<W: Write>(mut out: W, samples: &[f32], sample_rate: u32) -> io::Result<()> {
+    let data_len = samples.len() as u32 * 2;
+    out.write_all(b"RIFF")?;
+    out.write_all(&(36 + data_len).to_le_bytes())?;
+    out.write_all(b"WAVEfmt ")?;
+    out.write_all(&16u32.to_le_bytes())?;
+    out.write_all(&1u16.to_le_bytes())?; // PCM
+    out.write_all(&1u16.to_le_bytes())?; // mono
+    out.write_all(&sample_rate.to_le_bytes())?;
+    out.write_all(&(sample_rate * 2).to_le_bytes())?;
+    out.write_all(&2u16.to_le_bytes())?;
+    out.write_all(&16u16.to_le_bytes())?;
+    out.write_all(b"data")?;
+    out.write_all(&data_len.to_le_bytes())?;
+    for sample in samples {
+        let val = (sample.max(-1.0).min(1.0) * i16::MAX as f32) as i16;
+        out.write_all(&val.to_le_bytes())?;
+    }
+    Ok(())
+}
@@ -0,0 +1,216 @@
+use ggez::graphics::{self, Color, DrawMode, DrawParam, MeshBuilder, Rect};
+use ggez::nalgebra as na;
+use ggez::{Context, GameResult};
+use std::f32::consts::FRAC_PI_2;
+use std::vec::Vec;
+
+use crate::clock::SongClock;
+use crate::hands::Hand;
+use crate::layout::PianoLayout;
+use crate::song::{Song, Tile};
+
+/// Line segments used for each rounded corner.
+const CORNER_SEGMENTS: usize = 4;
+
+const LEFT_HAND_COLOR: (f32, f32, f32) = (1.0, 0.6, 0.3);
+const RIGHT_HAND_COLOR: (f32, f32, f32) = (0.4, 0.6, 1.0);
+
+const TRACK_COLORS: [(f32, f32, f32); 8] = [
+    (0.4, 0.6, 1.0),
+    (1.0, 0.6, 0.3),
+    (0.5, 0.9, 0.4),
+    (0.9, 0.4, 0.8),
+    (1.0, 0.9, 0.3),
+    (0.3, 0.9, 0.9),
+    (0.9, 0.4, 0.4),
+    (0.7, 0.7, 0.7),
+];
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ColorMode {
+    Hand,
+    Track,
+}
+
+/// Draws every visible tile, and the pedal markings, as one mesh.
+pub struct TileRenderer {
+    pub color_mode: ColorMode,
+    pub corner_radius: f32,
+}
+
+impl TileRenderer {
+    pub fn new() -> Self {
+        Self {
+            color_mode: ColorMode::Hand,
+            corner_radius: 4.0,
+        }
+    }
+
+    fn color(&self, tile: &Tile, dimmed: bool) -> Color {
+        let (r, g, b) = match self.color_mode {
+            ColorMode::Hand => match tile.hand {
+                Hand::Left => LEFT_HAND_COLOR,
+                Hand::Right => RIGHT_HAND_COLOR,
+            },
+            ColorMode::Track => TRACK_COLORS[tile.track % TRACK_COLORS.len()],
+        };
+        // Louder notes are drawn more solid.
+        let mut alpha = 0.35 + 0.65 * tile.velocity as f32 / 127.0;
+        if dimmed {
+            alpha *= 0.2;
+        }
+        Color::new(r, g, b, alpha)
+    }
+
+    /// Draw the tiles falling towards the piano. Tiles for notes other than `highlight`
+    /// are faded out, if it's given.
+    pub fn draw(
+        &self,
+        ctx: &mut Context,
+        song: &Song,
+        clock: &SongClock,
+        layout: &PianoLayout,
+        highlight: Option<u8>,
+    ) -> GameResult<()> {
+        let line = layout.keyboard_line();
+        let mut mesh = MeshBuilder::new();
+        let mut any = false;
+        for tile in song.active_tiles.iter() {
+            let (x, w) = match layout.note_x(tile.note) {
+                Some(x) => x,
+                None => continue,
+            };
+            let top = tile.vertical_position(clock, line);
+            let bottom = top + tile.vertical_height(clock, line);
+            let (top, bottom) = match visible_span(top, bottom, layout.screen.y, line) {
+                Some(span) => span,
+                None => continue,
+            };
+            let dimmed = highlight.map(|n| n != tile.note).unwrap_or(false);
+            let rect = Rect::new(x + 1.0, top, w - 2.0, bottom - top);
+            let points = rounded_rect(rect, self.corner_radius);
+            mesh.polygon(DrawMode::fill(), &points, self.color(tile, dimmed))?;
+            any = true;
+        }
+
+        // Pedal markings run down the left edge of the screen.
+        let now = clock.position();
+        for span in song.pedals.iter() {
+            if span.end <= now || span.start > now + clock.window() {
+                continue;
+            }
+            let top = clock.screen_y(span.end, line).max(layout.screen.y);
+            let bottom = clock.screen_y(span.start, line).min(line);
+            if bottom - top < 1.0 {
+                continue;
+            }
+            mesh.rectangle(
+                DrawMode::fill(),
+                Rect::new(layout.screen.x, top, 8.0, bottom - top),
+                Color::new(1.0, 0.85, 0.2, 0.5),
+            );
+            any = true;
+        }
+
+        if any {
+            let mesh = mesh.build(ctx)?;
+            graphics::draw(ctx, &mesh, DrawParam::default())?;
+        }
+        Ok(())
+    }
+}
+
+/// The part of a tile running from `top` to `bottom` that lies between the top of the
+/// screen and the keyboard line, if there's enough of it to draw.
+fn visible_span(top: f32, bottom: f32, screen_top: f32, line: f32) -> Option<(f32, f32)> {
+    let (top, bottom) = (top.max(screen_top), bottom.min(line));
+    if bottom - top < 1.0 {
+        None
+    } else {
+        Some((top, bottom))
+    }
+}
+
+/// The outline of `rect` with its corners rounded off, going clockwise.
+fn rounded_rect(rect: Rect, radius: f32) -> Vec<na::Point2<f32>> {
+    let r = radius.min(rect.w / 2.0).min(rect.h / 2.0).max(0.0);
+    let corners = [
+        (rect.x + rect.w - r, rect.y + r, -FRAC_PI_2),
+        (rect.x + rect.w - r, rect.y + rect.h - r, 0.0),
+        (rect.x + r, rect.y + rect.h - r, FRAC_PI_2),
+        (rect.x + r, rect.y + r, 2.0 * FRAC_PI_2),
+    ];
+    let mut points = Vec::with_capacity(corners.len() * (CORNER_SEGMENTS + 1));
+    for &(cx, cy, start) in corners.iter() {
+        for i in 0..=CORNER_SEGMENTS {
+            let angle = start + FRAC_PI_2 * i as f32 / CORNER_SEGMENTS as f32;
+            points.push(na::Point2::new(cx + r * angle.cos(), cy + r * angle.sin()));
+        }
+    }
+    points
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bounds(points: &[na::Point2<f32>]) -> (f32, f32, f32, f32) {
+        points.iter().fold(
+            (f32::MAX, f32::MAX, f32::MIN, f32::MIN),
+            |(left, top, right, bottom), p| {
+                (left.min(p.x), top.min(p.y), right.max(p.x), bottom.max(p.y))
+            },
+        )
+    }
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-3
+    }
+
+    #[test]
+    fn rounded_rect_keeps_to_its_bounds() {
+        let points = rounded_rect(Rect::new(10.0, 20.0, 30.0, 40.0), 4.0);
+        assert_eq!(points.len(), 4 * (CORNER_SEGMENTS + 1));
+        let (left, top, right, bottom) = bounds(&points);
+        assert!(close(left, 10.0) && close(top, 20.0));
+        assert!(close(right, 40.0) && close(bottom, 60.0));
+        // The corner itself is cut off.
+        assert!(points
+            .iter()
+            .all(|p| !(close(p.x, 10.0) && close(p.y, 20.0))));
+    }
+
+    #[test]
+    fn radius_is_clamped_on_thin_tiles() {
+        // Only 2 high, so the radius can be no more than 1.
+        let rect = Rect::new(0.0, 0.0, 30.0, 2.0);
+        let points = rounded_rect(rect, 4.0);
+        let (left, top, right, bottom) = bounds(&points);
+        assert!(close(left, 0.0) && close(top, 0.0));
+        assert!(close(right, 30.0) && close(bottom, 2.0));
+        // With a clamped radius the straight edges start 1 in from the corners.
+        assert!(points.iter().any(|p| close(p.x, 1.0) && close(p.y, 0.0)));
+        assert!(points.iter().any(|p| close(p.x, 29.0) && close(p.y, 2.0)));
+    }
+
+    #[test]
+    fn zero_radius_gives_square_corners() {
+        let points = rounded_rect(Rect::new(0.0, 0.0, 10.0, 10.0), 0.0);
+        assert!(points.iter().any(|p| close(p.x, 0.0) && close(p.y, 0.0)));
+        assert!(points.iter().any(|p| close(p.x, 10.0) && close(p.y, 10.0)));
+    }
+
+    #[test]
+    fn tiles_crossing_the_keyboard_line_are_cut_off_there() {
+        assert_eq!(visible_span(400.0, 600.0, 0.0, 500.0), Some((400.0, 500.0)));
+        assert_eq!(visible_span(-50.0, 100.0, 0.0, 500.0), Some((0.0, 100.0)));
+        assert_eq!(visible_span(-50.0, 900.0, 0.0, 500.0), Some((0.0, 500.0)));
+    }
+
+    #[test]
+    fn tiles_off_screen_or_too_thin_are_skipped() {
+        assert_eq!(visible_span(500.0, 700.0, 0.0, 500.0), None);
+        assert_eq!(visible_span(-200.0, -100.0, 0.0, 500.0), None);
+        assert_eq!(visible_span(499.5, 600.0, 0.0, 500.0), None);
+    }
+}
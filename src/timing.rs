@@ -0,0 +1,239 @@
+use midly::{Event, EventKind, Fps, MetaMessage, Timing};
+use std::time::Duration;
+use std::vec::Vec;
+
+use crate::midi_interpreter::to_abstime;
+
+/// Tempo assumed until the first `Tempo` event (120 bpm), in microseconds per beat.
+pub const DEFAULT_TEMPO: u32 = 500_000;
+
+#[derive(Debug, Copy, Clone)]
+enum Resolution {
+    /// Ticks per quarter note. Real time depends on the current tempo.
+    Metrical(u64),
+    /// Ticks per second. Tempo events have no effect.
+    Timecode(f64),
+}
+
+#[derive(Debug, Copy, Clone)]
+struct TempoChange {
+    tick: u32,
+    /// Absolute time of this change, in microseconds.
+    micros: u64,
+    /// Microseconds per beat from this change onwards.
+    tempo: u32,
+}
+
+/// Converts absolute tick counts into real time, following every tempo change in the file.
+pub struct TempoMap {
+    resolution: Resolution,
+    changes: Vec<TempoChange>,
+    /// Ticks at which the time signature changes, with the length of a measure
+    /// and the number of beats in it from there on.
+    signatures: Vec<(u32, u32, u32)>,
+}
+
+impl TempoMap {
+    /// Build a tempo map from the header timing and the tempo and time signature events of every track.
+    pub fn new(timing: Timing, tracks: &[Vec<Event<'_>>]) -> Self {
+        let resolution = match timing {
+            Timing::Metrical(ppq) => {
+                let ppq: u16 = ppq.into();
+                Resolution::Metrical(ppq.max(1) as u64)
+            }
+            Timing::Timecode(fps, subframes) => {
+                let fps = match fps {
+                    Fps::Fps24 => 24.0,
+                    Fps::Fps25 => 25.0,
+                    Fps::Fps29 => 30.0 / 1.001,
+                    Fps::Fps30 => 30.0,
+                };
+                Resolution::Timecode(fps * subframes.max(1) as f64)
+            }
+        };
+
+        // Timecode files have no notion of beats, so measure them at the default tempo.
+        let ticks_per_beat = match resolution {
+            Resolution::Metrical(ppq) => ppq as u32,
+            Resolution::Timecode(tps) => (tps * DEFAULT_TEMPO as f64 / 1_000_000.0) as u32,
+        }
+        .max(1);
+
+        let mut tempos: Vec<(u32, u32)> = vec![];
+        let mut signatures: Vec<(u32, u32, u32)> = vec![(0, ticks_per_beat * 4, 4)];
+        for track in tracks {
+            for ev in to_abstime(track.clone()) {
+                match ev.kind {
+                    EventKind::Meta(MetaMessage::Tempo(tempo)) => {
+                        tempos.push((ev.delta.into(), tempo.into()))
+                    }
+                    EventKind::Meta(MetaMessage::TimeSignature(num, denom, _, _)) => {
+                        let measure = (ticks_per_beat * 4 * num as u32) >> denom.min(31);
+                        signatures.push((ev.delta.into(), measure.max(1), (num as u32).max(1)));
+                    }
+                    _ => (),
+                }
+            }
+        }
+        tempos.sort_by_key(|i| i.0);
+        signatures.sort_by_key(|i| i.0);
+        signatures.dedup_by(|later, earlier| {
+            if later.0 == earlier.0 {
+                earlier.1 = later.1;
+                earlier.2 = later.2;
+                true
+            } else {
+                false
+            }
+        });
+
+        let mut map = Self {
+            resolution,
+            changes: vec![TempoChange {
+                tick: 0,
+                micros: 0,
+                tempo: DEFAULT_TEMPO,
+            }],
+            signatures,
+        };
+        for (tick, tempo) in tempos {
+            let micros = map.micros_at(tick);
+            let last = map.changes.last_mut().unwrap();
+            if last.tick == tick {
+                last.tempo = tempo;
+            } else {
+                map.changes.push(TempoChange {
+                    tick,
+                    micros,
+                    tempo,
+                });
+            }
+        }
+        map
+    }
+
+    fn micros_at(&self, tick: u32) -> u64 {
+        match self.resolution {
+            Resolution::Metrical(ppq) => {
+                let idx = match self.changes.binary_search_by_key(&tick, |i| i.tick) {
+                    Ok(idx) => idx,
+                    Err(idx) => idx - 1,
+                };
+                let change = &self.changes[idx];
+                change.micros + (tick - change.tick) as u64 * change.tempo as u64 / ppq
+            }
+            Resolution::Timecode(tps) => (tick as f64 * 1_000_000.0 / tps) as u64,
+        }
+    }
+
+    /// Real time elapsed from the start of the song until `tick`.
+    pub fn to_duration(&self, tick: u32) -> Duration {
+        Duration::from_micros(self.micros_at(tick))
+    }
+
+    /// Tick at which the zero-based `measure` starts.
+    pub fn measure_tick(&self, measure: u32) -> u32 {
+        let mut remaining = measure;
+        for (idx, &(tick, length, _)) in self.signatures.iter().enumerate() {
+            let end = self.signatures.get(idx + 1).map(|i| i.0);
+            let count = end.map(|end| (end - tick + length - 1) / length);
+            match count {
+                Some(count) if count <= remaining => remaining -= count,
+                _ => return tick.saturating_add(remaining.saturating_mul(length)),
+            }
+        }
+        0
+    }
+
+    /// Beats in the zero-based `measure`, going by the time signature it starts in.
+    pub fn beats_in_measure(&self, measure: u32) -> u32 {
+        let tick = self.measure_tick(measure);
+        self.signatures
+            .iter()
+            .take_while(|i| i.0 <= tick)
+            .last()
+            .map(|i| i.2)
+            .unwrap_or(4)
+    }
+
+    /// Real time at which the zero-based `measure` starts.
+    pub fn measure_start(&self, measure: u32) -> Duration {
+        self.to_duration(self.measure_tick(measure))
+    }
+
+    /// Zero-based measure containing the given real time.
+    pub fn measure_at(&self, time: Duration) -> u32 {
+        let mut measure = 0;
+        let mut start = self.measure_start(0);
+        loop {
+            let next = self.measure_start(measure + 1);
+            if next > time || next <= start {
+                return measure;
+            }
+            measure += 1;
+            start = next;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tempo(delta: u32, tempo: u32) -> Event<'static> {
+        Event {
+            delta: delta.into(),
+            kind: EventKind::Meta(MetaMessage::Tempo(tempo.into())),
+        }
+    }
+
+    fn metrical(ppq: u16, tracks: &[Vec<Event<'static>>]) -> TempoMap {
+        TempoMap::new(Timing::Metrical(ppq.into()), tracks)
+    }
+
+    #[test]
+    fn default_tempo_is_120_bpm() {
+        let map = metrical(480, &[]);
+        assert_eq!(map.to_duration(0), Duration::from_millis(0));
+        assert_eq!(map.to_duration(480), Duration::from_millis(500));
+        assert_eq!(map.to_duration(960), Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn tempo_changes_apply_from_their_tick() {
+        // 120 bpm for two beats, then 240 bpm.
+        let map = metrical(480, &[vec![tempo(960, 250_000)]]);
+        assert_eq!(map.to_duration(960), Duration::from_millis(1000));
+        assert_eq!(map.to_duration(1440), Duration::from_millis(1250));
+        assert_eq!(map.to_duration(1920), Duration::from_millis(1500));
+    }
+
+    #[test]
+    fn tempo_changes_from_every_track_are_merged() {
+        // Track deltas are relative, so the second change is at tick 960.
+        let conductor = vec![tempo(0, 1_000_000), tempo(960, 500_000)];
+        let other = vec![tempo(480, 250_000)];
+        let map = metrical(480, &[conductor, other]);
+        // 60 bpm for one beat, 240 bpm for one beat, then 120 bpm.
+        assert_eq!(map.to_duration(480), Duration::from_millis(1000));
+        assert_eq!(map.to_duration(960), Duration::from_millis(1250));
+        assert_eq!(map.to_duration(1440), Duration::from_millis(1750));
+    }
+
+    #[test]
+    fn measures_follow_tempo_changes() {
+        let map = metrical(480, &[vec![tempo(960, 250_000)]]);
+        assert_eq!(map.measure_tick(1), 1920);
+        assert_eq!(map.measure_start(1), Duration::from_millis(1500));
+        assert_eq!(map.measure_at(Duration::from_millis(1499)), 0);
+        assert_eq!(map.measure_at(Duration::from_millis(1500)), 1);
+    }
+
+    #[test]
+    fn timecode_ignores_tempo() {
+        // 25 frames of 40 ticks is 1000 ticks a second.
+        let map = TempoMap::new(Timing::Timecode(Fps::Fps25, 40), &[vec![tempo(0, 250_000)]]);
+        assert_eq!(map.to_duration(500), Duration::from_millis(500));
+        assert_eq!(map.to_duration(2000), Duration::from_millis(2000));
+    }
+}
@@ -0,0 +1,204 @@
+use crate::layout::KeyRange;
+use crate::midi_interpreter::DRUM_CHANNEL;
+use crate::song::Tile;
+
+/// What to do with notes that fall outside the player's keyboard.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum FitMode {
+    /// Leave them where they are.
+    Keep,
+    /// Move them by octaves until they're in range.
+    Fold,
+    /// Leave them out.
+    Drop,
+}
+
+/// Changes made to a song's notes so it can be played on a particular keyboard.
+/// The song's own notes are kept, so a transform can be changed or undone at any time.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Transform {
+    /// Semitones to move every pitched note by.
+    pub transpose: i8,
+    pub fit: FitMode,
+    pub range: KeyRange,
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self {
+            transpose: 0,
+            fit: FitMode::Keep,
+            range: KeyRange::FULL,
+        }
+    }
+}
+
+/// What a transform would do to the notes the player plays.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Preview {
+    pub total: usize,
+    /// Notes outside the range after transposing.
+    pub out_of_range: usize,
+    pub folded: usize,
+    pub dropped: usize,
+    /// The lowest and highest notes that would be left, if any.
+    pub span: Option<(u8, u8)>,
+}
+
+impl Transform {
+    /// Transpose `tile`, leaving drums alone. Notes moved off the end of MIDI's range are lost.
+    pub fn transpose(&self, tile: &Tile) -> Option<Tile> {
+        if tile.channel == DRUM_CHANNEL {
+            return Some(*tile);
+        }
+        let note = tile.note as i32 + self.transpose as i32;
+        if !(0..=127).contains(&note) {
+            return None;
+        }
+        Some(Tile {
+            note: note as u8,
+            ..*tile
+        })
+    }
+
+    /// Transpose `tile` and fit it to the range, for a note the player has to play.
+    pub fn apply(&self, tile: &Tile) -> Option<Tile> {
+        let mut tile = self.transpose(tile)?;
+        if self.range.contains(tile.note) {
+            return Some(tile);
+        }
+        match self.fit {
+            FitMode::Keep => Some(tile),
+            FitMode::Drop => None,
+            FitMode::Fold => {
+                while tile.note < self.range.low {
+                    tile.note += 12;
+                }
+                while tile.note > self.range.high {
+                    tile.note -= 12;
+                }
+                // Ranges narrower than an octave might not have the note at all.
+                if self.range.contains(tile.note) {
+                    Some(tile)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    pub fn preview(&self, tiles: &[Tile]) -> Preview {
+        let mut preview = Preview {
+            total: tiles.len(),
+            ..Preview::default()
+        };
+        for tile in tiles {
+            let moved = self.transpose(tile).filter(|i| self.range.contains(i.note));
+            if moved.is_none() {
+                preview.out_of_range += 1;
+            }
+            match self.apply(tile) {
+                Some(result) => {
+                    if moved.is_none() && self.fit == FitMode::Fold {
+                        preview.folded += 1;
+                    }
+                    preview.span = Some(match preview.span {
+                        Some((low, high)) => (low.min(result.note), high.max(result.note)),
+                        None => (result.note, result.note),
+                    });
+                }
+                None => preview.dropped += 1,
+            }
+        }
+        preview
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn tile(note: u8) -> Tile {
+        Tile::test(note, 0, 500)
+    }
+
+    /// C3 to C5, like a small keyboard.
+    const RANGE: KeyRange = KeyRange { low: 48, high: 72 };
+
+    fn transform(transpose: i8, fit: FitMode) -> Transform {
+        Transform {
+            transpose,
+            fit,
+            range: RANGE,
+        }
+    }
+
+    fn note(transform: Transform, note: u8) -> Option<u8> {
+        transform.apply(&tile(note)).map(|i| i.note)
+    }
+
+    #[test]
+    fn transposes_pitched_notes_only() {
+        let up = transform(3, FitMode::Keep);
+        assert_eq!(note(up, 60), Some(63));
+        let drum = Tile {
+            channel: DRUM_CHANNEL,
+            ..tile(36)
+        };
+        assert_eq!(up.transpose(&drum).map(|i| i.note), Some(36));
+        // Notes pushed off the end of MIDI's range are lost.
+        assert!(transform(10, FitMode::Keep).transpose(&tile(120)).is_none());
+    }
+
+    #[test]
+    fn keep_leaves_notes_outside_the_range() {
+        let keep = transform(0, FitMode::Keep);
+        assert_eq!(note(keep, 30), Some(30));
+        assert_eq!(note(keep, 90), Some(90));
+    }
+
+    #[test]
+    fn fold_moves_notes_by_octaves_into_the_range() {
+        let fold = transform(0, FitMode::Fold);
+        assert_eq!(note(fold, 60), Some(60));
+        assert_eq!(note(fold, 36), Some(48));
+        assert_eq!(note(fold, 25), Some(49));
+        assert_eq!(note(fold, 84), Some(72));
+        assert_eq!(note(fold, 100), Some(64));
+        // Transposing happens first, then folding.
+        assert_eq!(note(transform(-12, FitMode::Fold), 50), Some(50));
+    }
+
+    #[test]
+    fn fold_drops_notes_a_narrow_range_lacks() {
+        let narrow = Transform {
+            range: KeyRange { low: 60, high: 64 },
+            ..transform(0, FitMode::Fold)
+        };
+        assert_eq!(note(narrow, 74), Some(62));
+        assert_eq!(note(narrow, 66), None);
+    }
+
+    #[test]
+    fn drop_leaves_out_notes_outside_the_range() {
+        let dropping = transform(0, FitMode::Drop);
+        assert_eq!(note(dropping, 48), Some(48));
+        assert_eq!(note(dropping, 72), Some(72));
+        assert_eq!(note(dropping, 47), None);
+        assert_eq!(note(dropping, 73), None);
+        assert_eq!(note(transform(2, FitMode::Drop), 71), None);
+    }
+
+    #[test]
+    fn preview_counts_what_would_change() {
+        let tiles: Vec<Tile> = [30, 50, 60, 90].iter().map(|&i| tile(i)).collect();
+        let fold = transform(0, FitMode::Fold).preview(&tiles);
+        assert_eq!(
+            (fold.total, fold.out_of_range, fold.folded, fold.dropped),
+            (4, 2, 2, 0)
+        );
+        assert_eq!(fold.span, Some((50, 66)));
+        let dropped = transform(0, FitMode::Drop).preview(&tiles);
+        assert_eq!((dropped.folded, dropped.dropped), (0, 2));
+        assert_eq!(dropped.span, Some((50, 60)));
+    }
+}